@@ -0,0 +1,349 @@
+//! CSV and TSV exports, like AbuseIPDB's `ipAddress,abuseConfidenceScore,countryCode`, read by
+//! column.
+
+use crate::parser::{ParsedList, RawEntry};
+use crate::validate::InvalidEntry;
+
+/// Header names taken for the IP column when the request does not name one, compared without
+/// case, spaces, dashes or underscores.
+const IP_HEADERS: &[&str] = &[
+    "ip",
+    "ipaddress",
+    "ipaddr",
+    "address",
+    "cidr",
+    "network",
+    "srcip",
+    "sourceip",
+];
+
+/// A column of the list, by header name or 0-based index.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum Column {
+    Index(usize),
+    Name(String),
+}
+
+/// Only rows whose `column` has one of `values` are imported.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ColumnFilter {
+    pub column: Column,
+    /// Compared without case or surrounding whitespace.
+    pub values: Vec<String>,
+}
+
+/// Where the entries of a CSV or TSV list are, and which rows to take.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ColumnMapping {
+    /// The column with the IPs, found by its header name if not given.
+    pub ip: Option<Column>,
+    /// Whether the first row names the columns.
+    pub header: bool,
+    pub filters: Vec<ColumnFilter>,
+    /// The column with a score, such as a confidence or severity.
+    pub score: Option<Column>,
+    /// Rows whose score is lower are skipped.
+    pub min_score: Option<f64>,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        Self {
+            ip: None,
+            header: true,
+            filters: Vec::new(),
+            score: None,
+            min_score: None,
+        }
+    }
+}
+
+impl ColumnMapping {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_score.is_some() && self.score.is_none() {
+            return Err("`columns.min_score` needs a `columns.score` column".to_string());
+        }
+        if self.min_score.is_some_and(|min| !min.is_finite()) {
+            return Err("`columns.min_score` must be a finite number".to_string());
+        }
+        let named = self
+            .ip
+            .iter()
+            .chain(&self.score)
+            .chain(self.filters.iter().map(|filter| &filter.column))
+            .any(|column| matches!(column, Column::Name(_)));
+        if named && !self.header {
+            return Err("columns can only be named when the list has a header row".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Read the entries of a list of `delimiter` separated values.
+///
+/// Rows that don't pass the filters or the score threshold are counted in
+/// [`ParsedList::filtered`]. Rows too short to have the IP column, or with a score that is not a
+/// number, are reported as invalid.
+pub fn parse(input: &str, delimiter: char, mapping: &ColumnMapping) -> Result<ParsedList, String> {
+    mapping.validate()?;
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+    let mut rows = records(input, delimiter).into_iter();
+    let header = match mapping.header {
+        true => rows.next().map(|(_, fields)| fields).unwrap_or_default(),
+        false => Vec::new(),
+    };
+    let find = |column: &Column| -> Result<usize, String> {
+        match column {
+            Column::Index(index) => Ok(*index),
+            Column::Name(name) => header
+                .iter()
+                .position(|field| field.trim().eq_ignore_ascii_case(name.trim()))
+                .ok_or_else(|| format!("the list has no `{name}` column")),
+        }
+    };
+
+    let ip = match &mapping.ip {
+        Some(column) => find(column)?,
+        None => header
+            .iter()
+            .position(|field| IP_HEADERS.contains(&simplify(field).as_str()))
+            .ok_or("could not tell which column has the IPs, set `columns.ip`")?,
+    };
+    let score = mapping.score.as_ref().map(find).transpose()?;
+    let filters = mapping
+        .filters
+        .iter()
+        .map(|filter| Ok((find(&filter.column)?, &filter.values)))
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let mut list = ParsedList::default();
+    for (line, fields) in rows {
+        let field = |index: usize| fields.get(index).map_or("", |field| field.trim());
+        if ip >= fields.len() {
+            list.invalid.push(InvalidEntry {
+                line,
+                entry: fields.join(&delimiter.to_string()),
+                reason: format!("the row has no column {ip}"),
+            });
+            continue;
+        }
+        let wanted = filters.iter().all(|(column, values)| {
+            values
+                .iter()
+                .any(|value| value.trim().eq_ignore_ascii_case(field(*column)))
+        });
+        if !wanted {
+            list.filtered += 1;
+            continue;
+        }
+        if let (Some(column), Some(min)) = (score, mapping.min_score) {
+            match field(column).parse::<f64>() {
+                Ok(score) if score >= min => {}
+                Ok(_) => {
+                    list.filtered += 1;
+                    continue;
+                }
+                Err(_) => {
+                    list.invalid.push(InvalidEntry {
+                        line,
+                        entry: field(ip).to_string(),
+                        reason: format!("the score `{}` is not a number", field(column)),
+                    });
+                    continue;
+                }
+            }
+        }
+        list.entries.push(RawEntry {
+            line,
+            text: field(ip).to_string(),
+            reference: None,
+        });
+    }
+    Ok(list)
+}
+
+/// A header name without case, spaces, dashes or underscores.
+fn simplify(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '_'))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Split `input` into rows of fields, with the 1-based line each row starts on.
+///
+/// Fields may be quoted with `"`, in which case they can hold the delimiter, line breaks and `""`
+/// for a quote. Blank lines are skipped.
+fn records(input: &str, delimiter: char) -> Vec<(usize, Vec<String>)> {
+    let mut rows = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut row_line = 1;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            '\n' if quoted => {
+                line += 1;
+                field.push(c);
+            }
+            '\n' => {
+                fields.push(std::mem::take(&mut field));
+                if fields.iter().any(|field| !field.trim().is_empty()) {
+                    rows.push((row_line, std::mem::take(&mut fields)));
+                }
+                fields.clear();
+                line += 1;
+                row_line = line;
+            }
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            c if c == delimiter && !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    if fields.iter().any(|field| !field.trim().is_empty()) {
+        rows.push((row_line, fields));
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ABUSEIPDB: &str = "\
+ipAddress,abuseConfidenceScore,countryCode
+1.1.1.1,100,US
+2.2.2.2,40,CN
+3.3.3.3,90,RU
+";
+
+    fn mapping(json: &str) -> ColumnMapping {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn texts(list: &ParsedList) -> Vec<&str> {
+        list.entries
+            .iter()
+            .map(|entry| entry.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_ip_column_by_its_header() {
+        let list = parse(ABUSEIPDB, ',', &ColumnMapping::default()).unwrap();
+        assert_eq!(texts(&list), ["1.1.1.1", "2.2.2.2", "3.3.3.3"]);
+        assert_eq!(list.entries[0].line, 2);
+    }
+
+    #[test]
+    fn needs_to_be_told_an_unknown_ip_column() {
+        let error = parse("host,when\n1.1.1.1,now\n", ',', &ColumnMapping::default());
+        assert!(error.unwrap_err().contains("columns.ip"));
+        let list = parse(
+            "host,when\n1.1.1.1,now\n",
+            ',',
+            &mapping(r#"{"ip": "Host"}"#),
+        );
+        assert_eq!(texts(&list.unwrap()), ["1.1.1.1"]);
+    }
+
+    #[test]
+    fn reports_missing_columns() {
+        let error = parse(ABUSEIPDB, ',', &mapping(r#"{"score": "severity"}"#)).unwrap_err();
+        assert_eq!(error, "the list has no `severity` column");
+    }
+
+    #[test]
+    fn reads_by_index_without_a_header() {
+        let list = parse(
+            "x\t1.1.1.1\ny\t2.2.2.2\n",
+            '\t',
+            &mapping(r#"{"ip": 1, "header": false}"#),
+        )
+        .unwrap();
+        assert_eq!(texts(&list), ["1.1.1.1", "2.2.2.2"]);
+    }
+
+    #[test]
+    fn filters_rows_and_applies_the_score_threshold() {
+        let list = parse(
+            ABUSEIPDB,
+            ',',
+            &mapping(
+                r#"{
+                    "filters": [{"column": "countryCode", "values": [" us", "ru"]}],
+                    "score": "abuseConfidenceScore",
+                    "min_score": 95
+                }"#,
+            ),
+        )
+        .unwrap();
+        assert_eq!(texts(&list), ["1.1.1.1"]);
+        assert_eq!(list.filtered, 2);
+        assert!(list.invalid.is_empty());
+    }
+
+    #[test]
+    fn reports_bad_scores_and_short_rows() {
+        let input = "ip,score\n1.1.1.1,high\n\n2.2.2.2\n,\n3.3.3.3,5\n";
+        let mapping = mapping(r#"{"score": "score", "min_score": 1, "ip": 1, "header": true}"#);
+        let list = parse(input, ',', &mapping).unwrap();
+        let invalid: Vec<_> = list
+            .invalid
+            .iter()
+            .map(|entry| (entry.line, entry.reason.as_str()))
+            .collect();
+        assert_eq!(
+            invalid,
+            [
+                (2, "the score `high` is not a number"),
+                (4, "the row has no column 1")
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_inconsistent_mappings() {
+        assert!(mapping(r#"{"min_score": 5}"#).validate().is_err());
+        assert!(
+            mapping(r#"{"ip": "ip", "header": false}"#)
+                .validate()
+                .is_err()
+        );
+        assert!(mapping(r#"{"ip": 0, "header": false}"#).validate().is_ok());
+    }
+
+    #[test]
+    fn reads_quoted_fields() {
+        let input = "ip,note\r\n\"1.1.1.1\",\"says \"\"hi\"\", twice\"\r\n  \"2.2.2.2\" ,\"two\nlines\"\n3.3.3.3,x\n";
+        let rows = records(input, ',');
+        assert_eq!(rows[1].1, ["1.1.1.1", "says \"hi\", twice"]);
+        assert_eq!(rows[2].1, ["2.2.2.2 ", "two\nlines"]);
+        // rows are numbered by the line they start on
+        let lines: Vec<usize> = rows.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, [1, 2, 3, 5]);
+
+        let list = parse(input, ',', &ColumnMapping::default()).unwrap();
+        assert_eq!(texts(&list), ["1.1.1.1", "2.2.2.2", "3.3.3.3"]);
+    }
+
+    #[test]
+    fn header_names_are_compared_loosely() {
+        let list = parse("Source IP;x\n1.1.1.1;y\n", ';', &ColumnMapping::default()).unwrap();
+        assert_eq!(texts(&list), ["1.1.1.1"]);
+    }
+}
@@ -0,0 +1,495 @@
+//! Compressed lists: gzip, bzip2 and xz streams, and zip archives of one or more lists.
+//!
+//! Streams are decompressed as they arrive, on a blocking thread, so only the decompressed list is
+//! held in memory. A zip archive has its directory at the end, so it is read in full first.
+
+use std::borrow::Cow;
+use std::io::{Cursor, Read};
+
+use axum::body::Bytes;
+use futures_util::{Stream, StreamExt};
+use tokio::sync::mpsc;
+
+use crate::errors::Error;
+
+/// Largest list accepted once decompressed, so a small archive cannot exhaust memory.
+pub const MAX_DECOMPRESSED_SIZE: usize = 256 * 1024 * 1024;
+
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+/// Bytes needed to recognize every supported compression.
+const MAGIC_LENGTH: usize = XZ_MAGIC.len();
+/// Chunks of a compressed list received ahead of the decompressor.
+const CHUNKS_IN_FLIGHT: usize = 16;
+
+/// How a list is compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Bzip2,
+    Xz,
+    Zip,
+}
+
+impl Compression {
+    /// Recognize the compression of `data` by its first bytes, or by `content_type` if they are
+    /// not telling.
+    pub fn detect(data: &[u8], content_type: Option<&str>) -> Option<Self> {
+        if data.starts_with(&[0x1f, 0x8b]) {
+            return Some(Self::Gzip);
+        }
+        if data.starts_with(b"BZh") {
+            return Some(Self::Bzip2);
+        }
+        if data.starts_with(XZ_MAGIC) {
+            return Some(Self::Xz);
+        }
+        if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+            return Some(Self::Zip);
+        }
+        let media_type = content_type?.split(';').next()?.trim().to_ascii_lowercase();
+        match media_type.as_str() {
+            "application/gzip" | "application/x-gzip" => Some(Self::Gzip),
+            "application/x-bzip2" | "application/x-bzip" => Some(Self::Bzip2),
+            "application/x-xz" => Some(Self::Xz),
+            "application/zip" | "application/x-zip-compressed" => Some(Self::Zip),
+            _ => None,
+        }
+    }
+}
+
+/// The compressions decompressed as they stream in.
+#[derive(Clone, Copy, Debug)]
+enum Codec {
+    Gzip,
+    Bzip2,
+    Xz,
+}
+
+pub fn too_large(limit: usize) -> String {
+    format!("the list is larger than {limit} bytes once decompressed")
+}
+
+/// A list read from a stream of chunks, see [`read_list`].
+#[derive(Clone, Debug)]
+pub enum List {
+    /// The text of a list that was not compressed, or has been decompressed.
+    Text(String),
+    /// A zip archive, whose files are only chosen once the `members` to import are known.
+    Zip(Vec<u8>),
+}
+
+impl List {
+    /// The text of the list.
+    ///
+    /// `name` is what the list is called in errors, like the name of the upload. Of a zip
+    /// archive, the files matching `members` are imported, joined so each keeps its own lines, or
+    /// its only file if `members` is empty.
+    pub async fn into_text(self, name: &str, members: &[String]) -> Result<String, Error> {
+        match self {
+            Self::Text(text) => Ok(text),
+            Self::Zip(data) => {
+                let (name, members) = (name.to_string(), members.to_vec());
+                tokio::task::spawn_blocking(move || unzip(data, &name, &members))
+                    .await
+                    .map_err(|e| Error::InvalidList(e.to_string()))?
+            }
+        }
+    }
+}
+
+/// Read a list that may be compressed from `chunks`, decompressing it as it arrives.
+///
+/// `name` is what the list is called in errors. The stream is expected to enforce its own size
+/// limit, the decompressed list is limited to [`MAX_DECOMPRESSED_SIZE`].
+pub async fn read_list<S>(
+    mut chunks: S,
+    name: &str,
+    content_type: Option<&str>,
+) -> Result<List, Error>
+where
+    S: Stream<Item = Result<Bytes, Error>> + Unpin,
+{
+    let mut head = Vec::new();
+    while head.len() < MAGIC_LENGTH {
+        match chunks.next().await {
+            Some(chunk) => head.extend_from_slice(&chunk?),
+            None => break,
+        }
+    }
+
+    let codec = match Compression::detect(&head, content_type) {
+        None => {
+            let data = collect(head, chunks).await?;
+            return Ok(List::Text(String::from_utf8_lossy(&data).into_owned()));
+        }
+        Some(Compression::Zip) => return Ok(List::Zip(collect(head, chunks).await?)),
+        Some(Compression::Gzip) => Codec::Gzip,
+        Some(Compression::Bzip2) => Codec::Bzip2,
+        Some(Compression::Xz) => Codec::Xz,
+    };
+
+    let (sender, receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    let owned_name = name.to_string();
+    let decoder =
+        tokio::task::spawn_blocking(move || decode(codec, ChunkReader::new(receiver), &owned_name));
+    let mut chunk = Some(Ok(Bytes::from(head)));
+    while let Some(next) = chunk {
+        // the decompressor stops taking chunks once it has failed
+        if sender.send(next?).await.is_err() {
+            break;
+        }
+        chunk = chunks.next().await;
+    }
+    drop(sender);
+
+    let text = decoder
+        .await
+        .map_err(|e| Error::InvalidList(format!("could not decompress {name}: {e}")))??;
+    Ok(List::Text(text))
+}
+
+async fn collect<S>(mut data: Vec<u8>, mut chunks: S) -> Result<Vec<u8>, Error>
+where
+    S: Stream<Item = Result<Bytes, Error>> + Unpin,
+{
+    while let Some(chunk) = chunks.next().await {
+        data.extend_from_slice(&chunk?);
+    }
+    Ok(data)
+}
+
+/// The chunks of a compressed list, read on a blocking thread as they arrive.
+struct ChunkReader {
+    receiver: mpsc::Receiver<Bytes>,
+    chunk: Bytes,
+}
+
+impl ChunkReader {
+    fn new(receiver: mpsc::Receiver<Bytes>) -> Self {
+        Self {
+            receiver,
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+/// Decompress a gzip, bzip2 or xz stream of one or more members.
+fn decode(codec: Codec, reader: impl Read, name: &str) -> Result<String, Error> {
+    let failed = |e: String| Error::InvalidList(format!("could not decompress {name}: {e}"));
+    let decoder: Box<dyn Read> = match codec {
+        Codec::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+        Codec::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+        Codec::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(reader)),
+    };
+    let data = read_limited(decoder, MAX_DECOMPRESSED_SIZE).map_err(failed)?;
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// Read `reader` to the end, failing once it gives more than `limit` bytes.
+fn read_limited(reader: impl Read, limit: usize) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    // one byte over the limit is enough to tell the list is too large
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut data)
+        .map_err(|e| e.to_string())?;
+    if data.len() > limit {
+        return Err(too_large(limit));
+    }
+    Ok(data)
+}
+
+/// The text of the files of a zip archive that match `members`.
+fn unzip(data: Vec<u8>, name: &str, members: &[String]) -> Result<String, Error> {
+    let failed = |e: String| Error::InvalidList(format!("could not decompress {name}: {e}"));
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(|e| failed(e.to_string()))?;
+    let names = archive
+        .file_names()
+        .map(|file| file.map(Cow::into_owned))
+        .collect::<Result<Vec<String>, _>>()
+        .map_err(|e| failed(e.to_string()))?;
+    // the files of the archive, with their index, leaving out directories
+    let files: Vec<(usize, String)> = names
+        .into_iter()
+        .enumerate()
+        .filter(|(_, file)| !file.ends_with('/'))
+        .collect();
+    let chosen = choose(&files, members).ok_or_else(|| Error::ChooseArchiveMembers {
+        archive: name.to_string(),
+        members: files.iter().map(|(_, file)| file.clone()).collect(),
+    })?;
+
+    let mut out = Vec::new();
+    for (i, file) in chosen {
+        if !out.is_empty() {
+            out.push(b'\n');
+        }
+        let limit = MAX_DECOMPRESSED_SIZE.saturating_sub(out.len());
+        // the archive checks each file against its checksum once it is read to the end
+        let contents = archive
+            .by_index(*i)
+            .map_err(|e| e.to_string())
+            .and_then(|reader| read_limited(reader, limit))
+            .map_err(|e| failed(format!("{file}: {e}")))?;
+        out.extend_from_slice(&contents);
+    }
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+/// The files matching any of `patterns`, or the only file if there are no patterns. `None` if
+/// that leaves no file, or several files and no patterns.
+fn choose<'a>(
+    files: &'a [(usize, String)],
+    patterns: &[String],
+) -> Option<Vec<&'a (usize, String)>> {
+    let chosen: Vec<&(usize, String)> = match patterns {
+        [] => files.iter().collect(),
+        patterns => files
+            .iter()
+            .filter(|(_, file)| patterns.iter().any(|pattern| glob(pattern.trim(), file)))
+            .collect(),
+    };
+    match chosen.len() {
+        0 => None,
+        1 => Some(chosen),
+        _ if patterns.is_empty() => None,
+        _ => Some(chosen),
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any run of characters and `?` for any
+/// single one.
+fn glob(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+    // the last `*` seen, and where in `name` it was last tried
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use futures_util::stream;
+    use zip::write::SimpleFileOptions;
+
+    use super::*;
+
+    const LIST: &str = "1.1.1.1\n10.0.0.0/8\n2001:db8::/32\n";
+
+    /// Read `data` in small chunks, so magic bytes and streams are split across them.
+    async fn read(data: &[u8], members: &[&str]) -> Result<String, Error> {
+        let chunks: Vec<Result<Bytes, Error>> = data
+            .chunks(3)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let members: Vec<String> = members.iter().map(|member| member.to_string()).collect();
+        read_list(stream::iter(chunks), "list", None)
+            .await?
+            .into_text("list", &members)
+            .await
+    }
+
+    fn gzip(text: &str) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn bzip2(text: &str) -> Vec<u8> {
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn xz(text: &str) -> Vec<u8> {
+        let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 1);
+        encoder.write_all(text.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zip(files: &[(&str, &str)], method: zip::CompressionMethod) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default().compression_method(method);
+        for (name, contents) in files {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[tokio::test]
+    async fn plain_text_is_read_as_is() {
+        assert_eq!(read(LIST.as_bytes(), &[]).await.unwrap(), LIST);
+        assert_eq!(read(b"", &[]).await.unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn streams_round_trip() {
+        for (data, format) in [
+            (gzip(LIST), "gzip"),
+            (bzip2(LIST), "bzip2"),
+            (xz(LIST), "xz"),
+        ] {
+            assert_eq!(read(&data, &[]).await.unwrap(), LIST, "{format}");
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_of_several_members_are_read_to_the_end() {
+        for compress in [gzip, bzip2, xz] {
+            let data = [compress("1.1.1.1\n"), compress("2.2.2.2\n")].concat();
+            assert_eq!(read(&data, &[]).await.unwrap(), "1.1.1.1\n2.2.2.2\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn corrupt_streams_are_rejected() {
+        for compress in [gzip, bzip2, xz] {
+            let data = compress(LIST);
+            let truncated = &data[..data.len() - 4];
+            let err = read(truncated, &[]).await.unwrap_err();
+            assert!(matches!(err, Error::InvalidList(_)), "{err}");
+
+            let mut flipped = data.clone();
+            let middle = flipped.len() / 2;
+            flipped[middle] ^= 0xff;
+            let err = read(&flipped, &[]).await.unwrap_err();
+            assert!(matches!(err, Error::InvalidList(_)), "{err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn the_only_file_of_an_archive_is_imported() {
+        for method in [
+            zip::CompressionMethod::Stored,
+            zip::CompressionMethod::Deflated,
+            zip::CompressionMethod::Bzip2,
+        ] {
+            let data = zip(&[("dir/", ""), ("list.txt", LIST)], method);
+            assert_eq!(read(&data, &[]).await.unwrap(), LIST, "{method}");
+        }
+    }
+
+    #[tokio::test]
+    async fn members_choose_the_files_of_an_archive() {
+        let files = [
+            ("a.txt", "1.1.1.1"),
+            ("b.txt", "2.2.2.2"),
+            ("c.csv", "3.3.3.3"),
+        ];
+        let data = zip(&files, zip::CompressionMethod::Deflated);
+
+        match read(&data, &[]).await.unwrap_err() {
+            Error::ChooseArchiveMembers { members, .. } => {
+                assert_eq!(members, ["a.txt", "b.txt", "c.csv"]);
+            }
+            err => panic!("{err}"),
+        }
+        assert_eq!(read(&data, &["*.txt"]).await.unwrap(), "1.1.1.1\n2.2.2.2");
+        assert_eq!(read(&data, &[" c.csv "]).await.unwrap(), "3.3.3.3");
+        assert!(matches!(
+            read(&data, &["*.json"]).await.unwrap_err(),
+            Error::ChooseArchiveMembers { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn corrupt_archives_are_rejected() {
+        let data = zip(&[("list.txt", LIST)], zip::CompressionMethod::Stored);
+        // the stored contents no longer match their checksum
+        let start = data
+            .windows(LIST.len())
+            .position(|window| window == LIST.as_bytes())
+            .unwrap();
+        let mut flipped = data.clone();
+        flipped[start] = b'9';
+        let err = read(&flipped, &[]).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidList(_)), "{err}");
+
+        let err = read(&data[..data.len() - 10], &[]).await.unwrap_err();
+        assert!(matches!(err, Error::InvalidList(_)), "{err}");
+    }
+
+    #[tokio::test]
+    async fn errors_of_the_stream_are_passed_on() {
+        let chunks = vec![
+            Ok(Bytes::from(gzip(LIST))),
+            Err(Error::InvalidRequest("cut off".to_string())),
+        ];
+        let err = read_list(stream::iter(chunks), "list", None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidRequest(_)), "{err}");
+    }
+
+    #[test]
+    fn output_is_limited() {
+        assert_eq!(read_limited(&b"1234"[..], 4).unwrap(), b"1234");
+        assert_eq!(read_limited(&b"12345"[..], 4).unwrap_err(), too_large(4));
+    }
+
+    #[test]
+    fn compression_is_detected_by_magic_bytes_then_content_type() {
+        assert_eq!(
+            Compression::detect(&gzip(""), None),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(Compression::detect(&xz(""), None), Some(Compression::Xz));
+        assert_eq!(
+            Compression::detect(b"1.1.1.1", Some("application/x-bzip2")),
+            Some(Compression::Bzip2)
+        );
+        assert_eq!(
+            Compression::detect(b"PK\x03\x04", Some("text/plain")),
+            Some(Compression::Zip)
+        );
+        assert_eq!(Compression::detect(b"1.1.1.1", Some("text/plain")), None);
+    }
+
+    #[test]
+    fn glob_matches_wildcards() {
+        assert!(glob("*.txt", "list.txt"));
+        assert!(glob("list-?.txt", "list-1.txt"));
+        assert!(glob("*", "anything"));
+        assert!(glob("a*b*c", "aXbYbZc"));
+        assert!(!glob("*.txt", "list.csv"));
+        assert!(!glob("list-?.txt", "list-12.txt"));
+    }
+}
@@ -2,31 +2,90 @@ use axum::response::IntoResponse;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("Zoraxy API error: {0}")]
-    ZoraxyApiError(#[from] reqwest::Error),
-    #[error("Import already in progress")]
-    ImportInProgress,
+    #[error("Zoraxy API {}: {message}", kind.as_str())]
+    ZoraxyResponse {
+        kind: crate::zoraxy_client::FailureKind,
+        message: String,
+    },
+    #[error("Job {0} not found")]
+    JobNotFound(crate::jobs::JobId),
+    #[error("Job {0} has already finished")]
+    JobFinished(crate::jobs::JobId),
+    #[error("Job {0} has not finished yet")]
+    JobNotFinished(crate::jobs::JobId),
+    #[error("Job {job} is already being rolled back by job {by}")]
+    AlreadyRolledBack {
+        job: crate::jobs::JobId,
+        by: crate::jobs::JobId,
+    },
+    #[error("Job {0} made no changes to roll back")]
+    NothingToRollBack(crate::jobs::JobId),
+    #[error("The blocklist does not contain any valid IP addresses or networks")]
+    NoValidEntries(Vec<crate::validate::InvalidEntry>),
+    #[error("Every valid entry of the blocklist overlaps the safelist")]
+    OnlySafelistedEntries(Vec<crate::safelist::Conflict>),
+    #[error("Could not read the list: {0}")]
+    InvalidList(String),
+    #[error("Choose which files of {archive} to import with `members`, it has: {}", members.join(", "))]
+    ChooseArchiveMembers {
+        archive: String,
+        members: Vec<String>,
+    },
+    #[error("Invalid import request: {0}")]
+    InvalidRequest(String),
+    #[error("Invalid settings: {0}")]
+    InvalidSettings(String),
+    #[error("Feed {0} not found")]
+    FeedNotFound(crate::feeds::FeedId),
+    #[error("Invalid feed: {0}")]
+    InvalidFeed(String),
+    #[error("Could not fetch feed: {0}")]
+    FeedFetch(String),
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
         let (status, error_message) = match &self {
-            Error::ZoraxyApiError(_) => (
+            Error::ZoraxyResponse { .. } | Error::FeedFetch(_) => (
                 axum::http::StatusCode::BAD_GATEWAY,
                 self.to_string().clone(),
             ),
 
-            Error::ImportInProgress => (
-                axum::http::StatusCode::CONFLICT,
-                "Import already in progress".to_string(),
-            ),
+            Error::JobFinished(_)
+            | Error::JobNotFinished(_)
+            | Error::AlreadyRolledBack { .. }
+            | Error::NothingToRollBack(_) => (axum::http::StatusCode::CONFLICT, self.to_string()),
+
+            Error::JobNotFound(_) | Error::FeedNotFound(_) => {
+                (axum::http::StatusCode::NOT_FOUND, self.to_string())
+            }
+
+            Error::NoValidEntries(_)
+            | Error::OnlySafelistedEntries(_)
+            | Error::InvalidList(_)
+            | Error::ChooseArchiveMembers { .. }
+            | Error::InvalidRequest(_)
+            | Error::InvalidSettings(_)
+            | Error::InvalidFeed(_) => (axum::http::StatusCode::BAD_REQUEST, self.to_string()),
         };
 
         tracing::error!("Error occurred: {}", error_message);
 
-        let body = axum::Json(serde_json::json!({
+        let mut body = serde_json::json!({
             "error": error_message,
-        }));
+        });
+        // point out exactly which lines were wrong
+        match &self {
+            Error::NoValidEntries(invalid) => body["invalid_entries"] = serde_json::json!(invalid),
+            Error::OnlySafelistedEntries(conflicts) => {
+                body["safelisted_entries"] = serde_json::json!(conflicts)
+            }
+            Error::ChooseArchiveMembers { members, .. } => {
+                body["members"] = serde_json::json!(members)
+            }
+            _ => {}
+        }
+        let body = axum::Json(body);
 
         (status, body).into_response()
     }
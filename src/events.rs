@@ -0,0 +1,149 @@
+use std::convert::Infallible;
+
+use axum::debug_handler;
+use axum::extract::{Query, State};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::stream::{self, Stream};
+use tokio::sync::broadcast;
+
+use crate::AppState;
+use crate::jobs::{Job, JobId};
+use crate::plan::Change;
+
+/// How many events a slow subscriber may fall behind before it misses some.
+const CAPACITY: usize = 1024;
+
+/// Something that happened to a job, streamed to the UI.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobEvent {
+    /// The job was created, or its state changed.
+    Job { job: Job },
+    /// A change of the job was applied or failed.
+    Progress {
+        job: Job,
+        /// Index of the change in the job's plan.
+        index: usize,
+        change: Change,
+        /// The reason the change failed, if it did.
+        error: Option<String>,
+        /// Changes per second since the job (re)started.
+        throughput: f64,
+        /// Estimated seconds until the job is done.
+        eta_secs: Option<u64>,
+    },
+}
+
+impl JobEvent {
+    fn job(&self) -> &Job {
+        match self {
+            JobEvent::Job { job } | JobEvent::Progress { job, .. } => job,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            JobEvent::Job { .. } => "job",
+            JobEvent::Progress { .. } => "progress",
+        }
+    }
+}
+
+/// Broadcasts [`JobEvent`]s to every connected UI.
+#[derive(Clone, Debug)]
+pub struct Events(broadcast::Sender<JobEvent>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+}
+
+impl Events {
+    pub fn publish(&self, event: JobEvent) {
+        // no one may be listening
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<JobEvent> {
+        self.0.subscribe()
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct EventsQuery {
+    /// Only stream events of this job.
+    pub job_id: Option<JobId>,
+}
+
+/// Stream job events as Server-Sent Events, named `job` and `progress`.
+///
+/// A subscriber that falls too far behind gets a `lagged` event with the number of events it
+/// missed, and should reload the jobs.
+#[debug_handler]
+pub async fn handle_job_events(
+    State(state): State<AppState>,
+    Query(query): Query<EventsQuery>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = stream::unfold(state.jobs.subscribe(), move |mut rx| async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) if query.job_id.is_some_and(|id| id != event.job().id) => continue,
+                Ok(event) => Event::default()
+                    .event(event.name())
+                    .json_data(&event)
+                    .expect("job events serialize"),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    Event::default().event("lagged").data(missed.to_string())
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            return Some((Ok(event), rx));
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+
+    use super::*;
+    use crate::plan::ImportMode;
+    use crate::queue::Priority;
+    use crate::validate::Validated;
+
+    #[tokio::test]
+    async fn only_events_of_the_requested_job_are_streamed() {
+        let ctx = AppState::for_tests("events");
+        let query = EventsQuery { job_id: Some(2) };
+        let events = handle_job_events(State(ctx.clone()), Query(query)).await;
+        for rule in ["a", "b"] {
+            let validated = Validated::default();
+            let job = ctx
+                .jobs
+                .create(
+                    rule.to_string(),
+                    ImportMode::Add,
+                    &validated,
+                    None,
+                    Priority::Normal,
+                )
+                .await;
+            ctx.jobs.mark_running(job.id).await;
+        }
+        // the stream ends once the last of the plugin's state is gone
+        drop(ctx);
+
+        let body = axum::body::to_bytes(events.into_response().into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let events: Vec<&str> = body.split("\n\n").filter(|e| !e.is_empty()).collect();
+        assert_eq!(events.len(), 2, "{body}");
+        for event in events {
+            assert!(event.starts_with("event: job\ndata: {"), "{event}");
+            assert!(event.contains(r#""id":2,"access_rule_id":"b""#), "{event}");
+        }
+    }
+}
@@ -0,0 +1,1017 @@
+use std::collections::{BTreeMap, HashMap};
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+use axum::{Json, debug_handler};
+use futures_util::{Stream, StreamExt, future, stream};
+use reqwest::{StatusCode, Url, header};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncReadExt;
+use tokio::sync::RwLock;
+use tokio::task::JoinSet;
+
+use crate::errors::Error;
+use crate::jobs::{JobId, JobState, unix_now};
+use crate::parser::{ListFormat, ListOptions};
+use crate::plan::ImportMode;
+use crate::queue::Priority;
+use crate::store::Changes;
+use crate::{AppState, ImportStarted, decompress, import, parser, validate};
+
+pub type FeedId = u64;
+
+/// Shortest refresh interval a feed may have, so a typo can't hammer the list's host.
+pub const MIN_INTERVAL_SECS: u64 = 60;
+/// How often the scheduler looks for feeds that are due.
+const SCHEDULER_TICK: Duration = Duration::from_secs(15);
+/// Feeds the scheduler refreshes at once, more wait for a later tick.
+const MAX_REFRESHES: usize = 4;
+/// How long fetching and decompressing a feed may take.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(120);
+/// Bytes read from a local feed at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A blocklist that is fetched from a URL and imported into an Access Rule on a schedule.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Feed {
+    pub id: FeedId,
+    /// Where the list is fetched from, `http`, `https` or `file`. Files must be in the
+    /// [`FeedRegistry`]'s directory of local lists.
+    pub url: String,
+    pub access_rule_id: String,
+    pub mode: ImportMode,
+    #[serde(default)]
+    pub format: ListFormat,
+    #[serde(flatten)]
+    pub options: ListOptions,
+    pub interval_secs: u64,
+    /// Disabled feeds are kept, but only refreshed on request.
+    pub enabled: bool,
+    pub created_at: u64,
+    /// When the feed was last fetched, successfully or not.
+    pub last_checked_at: Option<u64>,
+    /// Why the last refresh did not start an import, if it didn't.
+    pub last_error: Option<String>,
+    /// The import started by the last successful refresh.
+    pub last_job_id: Option<JobId>,
+    /// When a refresh last found the list changed and started an import.
+    pub last_changed_at: Option<u64>,
+    /// `ETag` of the last imported copy, sent back as `If-None-Match`.
+    pub etag: Option<String>,
+    /// `Last-Modified` of the last imported copy, sent back as `If-Modified-Since`.
+    pub last_modified: Option<String>,
+    /// SHA-256 of the entries of the last imported copy, see [`content_hash`].
+    pub content_hash: Option<String>,
+}
+
+impl Feed {
+    /// Whether the scheduler should refresh the feed at `now`.
+    fn is_due(&self, now: u64) -> bool {
+        self.enabled
+            && self
+                .last_checked_at
+                .is_none_or(|checked| now >= checked + self.interval_secs)
+    }
+}
+
+/// Body of a request to subscribe to a feed.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct NewFeed {
+    pub url: String,
+    pub access_rule_id: String,
+    #[serde(default)]
+    pub mode: ImportMode,
+    #[serde(default)]
+    pub format: ListFormat,
+    #[serde(flatten)]
+    pub options: ListOptions,
+    pub interval_secs: u64,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl NewFeed {
+    /// Check the feed can be subscribed to, with local lists read from `local_lists`.
+    fn validate(&self, local_lists: &std::path::Path) -> Result<(), String> {
+        let url = Url::parse(self.url.trim()).map_err(|e| format!("invalid `url`: {e}"))?;
+        match url.scheme() {
+            "http" | "https" => {}
+            "file" => drop(local_path(&url, local_lists)?),
+            scheme => {
+                return Err(format!(
+                    "unsupported URL scheme `{scheme}`, expected http, https or file"
+                ));
+            }
+        }
+        if self.access_rule_id.trim().is_empty() {
+            return Err("missing `access_rule_id`".to_string());
+        }
+        if self.interval_secs < MIN_INTERVAL_SECS {
+            return Err(format!(
+                "`interval_secs` must be at least {MIN_INTERVAL_SECS}"
+            ));
+        }
+        self.options.validate()
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Registry {
+    next_id: FeedId,
+    pub feeds: BTreeMap<FeedId, Feed>,
+}
+
+/// Shared record of every feed subscription, saved by the [`crate::store`].
+#[derive(Clone, Debug)]
+pub struct FeedRegistry {
+    inner: Arc<RwLock<Registry>>,
+    changes: Changes,
+    /// The only directory `file://` feeds may read from, so the API can't be used to read any
+    /// file the plugin can.
+    local_lists: Arc<std::path::PathBuf>,
+}
+
+impl FeedRegistry {
+    pub fn new(registry: Registry, changes: Changes, local_lists: std::path::PathBuf) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(registry)),
+            changes,
+            local_lists: Arc::new(local_lists),
+        }
+    }
+
+    pub async fn snapshot(&self) -> Registry {
+        self.inner.read().await.clone()
+    }
+
+    pub async fn create(&self, new: NewFeed) -> Feed {
+        let mut registry = self.inner.write().await;
+        registry.next_id += 1;
+        let feed = Feed {
+            id: registry.next_id,
+            url: new.url.trim().to_string(),
+            access_rule_id: new.access_rule_id.trim().to_string(),
+            mode: new.mode,
+            format: new.format,
+            options: new.options,
+            interval_secs: new.interval_secs,
+            enabled: new.enabled,
+            created_at: unix_now(),
+            last_checked_at: None,
+            last_error: None,
+            last_job_id: None,
+            last_changed_at: None,
+            etag: None,
+            last_modified: None,
+            content_hash: None,
+        };
+        registry.feeds.insert(feed.id, feed.clone());
+        self.changes.notify();
+        feed
+    }
+
+    pub async fn get(&self, id: FeedId) -> Option<Feed> {
+        self.inner.read().await.feeds.get(&id).cloned()
+    }
+
+    pub async fn list(&self) -> Vec<Feed> {
+        self.inner.read().await.feeds.values().cloned().collect()
+    }
+
+    pub async fn remove(&self, id: FeedId) -> Option<Feed> {
+        let feed = self.inner.write().await.feeds.remove(&id);
+        self.changes.notify();
+        feed
+    }
+
+    /// Apply `f` to the feed with the given ID, if it exists.
+    pub async fn update(&self, id: FeedId, f: impl FnOnce(&mut Feed)) {
+        if let Some(feed) = self.inner.write().await.feeds.get_mut(&id) {
+            f(feed);
+            self.changes.notify();
+        }
+    }
+}
+
+/// What a fetch of a feed returned.
+enum Fetched {
+    /// The server confirmed the list has not changed since the last import.
+    NotModified,
+    Body {
+        list: decompress::List,
+        etag: Option<String>,
+        last_modified: Option<String>,
+    },
+}
+
+/// The file a `file://` feed reads, which must be inside `local_lists` once symlinks and `..`
+/// are resolved.
+fn local_path(url: &Url, local_lists: &std::path::Path) -> Result<std::path::PathBuf, String> {
+    let path = url
+        .to_file_path()
+        .map_err(|()| format!("{url} is not a local path"))?;
+    let root = local_lists.canonicalize().map_err(|e| {
+        format!(
+            "local lists are read from {}, which can't be opened: {e}",
+            local_lists.display()
+        )
+    })?;
+    let resolved = path
+        .canonicalize()
+        .map_err(|e| format!("could not open {}: {e}", path.display()))?;
+    if !resolved.starts_with(&root) || !resolved.is_file() {
+        return Err(format!(
+            "{} is not a file in {}, the only directory local lists are read from",
+            path.display(),
+            local_lists.display()
+        ));
+    }
+    Ok(resolved)
+}
+
+/// Fetch the current contents of a feed, decompressing them as they arrive. Local lists are only
+/// read from `local_lists`.
+///
+/// With `conditional`, the validators of the last imported copy are sent along so the server can
+/// answer `304 Not Modified` instead of sending the list again.
+async fn fetch(
+    client: &reqwest::Client,
+    feed: &Feed,
+    conditional: bool,
+    local_lists: &std::path::Path,
+) -> Result<Fetched, Error> {
+    let url = Url::parse(&feed.url).map_err(|e| Error::FeedFetch(e.to_string()))?;
+    let mut etag = None;
+    let mut last_modified = None;
+    let list = match url.scheme() {
+        "file" => {
+            let path = local_path(&url, local_lists).map_err(Error::FeedFetch)?;
+            let error = |e| Error::FeedFetch(format!("could not read {}: {e}", path.display()));
+            let file = tokio::fs::File::open(&path).await.map_err(error)?;
+            let chunks = stream::unfold(Some(file), |file| async move {
+                let mut file = file?;
+                let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+                match (&mut file)
+                    .take(CHUNK_SIZE as u64)
+                    .read_to_end(&mut chunk)
+                    .await
+                {
+                    Ok(0) => None,
+                    Ok(_) => Some((Ok(Bytes::from(chunk)), Some(file))),
+                    Err(e) => Some((Err(e), None)),
+                }
+            });
+            let chunks = pin!(limited(chunks.map(|chunk| chunk.map_err(error))));
+            decompress::read_list(chunks, &feed.url, None).await?
+        }
+        _ => {
+            let mut request = client.get(url).timeout(FETCH_TIMEOUT);
+            if conditional {
+                if let Some(etag) = &feed.etag {
+                    request = request.header(header::IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &feed.last_modified {
+                    request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+            let response = request
+                .send()
+                .await
+                .map_err(|e| Error::FeedFetch(e.to_string()))?;
+            let status = response.status();
+            if status == StatusCode::NOT_MODIFIED {
+                return Ok(Fetched::NotModified);
+            }
+            if !status.is_success() {
+                return Err(Error::FeedFetch(format!(
+                    "the server responded with {status}"
+                )));
+            }
+            let validator = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string)
+            };
+            etag = validator(header::ETAG);
+            last_modified = validator(header::LAST_MODIFIED);
+            let content_type = validator(header::CONTENT_TYPE);
+            let chunks = stream::unfold(Some(response), |response| async move {
+                let mut response = response?;
+                match response.chunk().await {
+                    Ok(Some(chunk)) => Some((Ok(chunk), Some(response))),
+                    Ok(None) => None,
+                    Err(e) => Some((Err(Error::FeedFetch(e.to_string())), None)),
+                }
+            });
+            let chunks = pin!(limited(chunks));
+            decompress::read_list(chunks, &feed.url, content_type.as_deref()).await?
+        }
+    };
+    Ok(Fetched::Body {
+        list,
+        etag,
+        last_modified,
+    })
+}
+
+/// Fail once `chunks` add up to more than [`crate::MAX_IMPORT_BODY_SIZE`].
+fn limited(
+    chunks: impl Stream<Item = Result<Bytes, Error>>,
+) -> impl Stream<Item = Result<Bytes, Error>> {
+    chunks.scan(0, |total, chunk| {
+        let chunk = chunk.and_then(|chunk| {
+            *total += chunk.len();
+            match *total > crate::MAX_IMPORT_BODY_SIZE {
+                true => Err(Error::FeedFetch(too_large())),
+                false => Ok(chunk),
+            }
+        });
+        future::ready(Some(chunk))
+    })
+}
+
+fn too_large() -> String {
+    format!(
+        "the list is larger than {} bytes",
+        crate::MAX_IMPORT_BODY_SIZE
+    )
+}
+
+/// Fingerprint of the entries of a list.
+///
+/// Entries are normalized, deduplicated and sorted first, so a list whose only change is a
+/// regenerated comment header or a different order still counts as unchanged.
+fn content_hash(entries: &[ipnet::IpNet]) -> String {
+    let mut entries: Vec<String> = entries.iter().map(validate::canonical).collect();
+    entries.sort_unstable();
+    entries.dedup();
+    let digest = Sha256::digest(entries.join("\n").as_bytes());
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Outcome of a refresh that did not fail.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Refresh {
+    /// The list changed, and an import of it was started.
+    Imported(ImportStarted),
+    /// The list is the same as the last one imported, so nothing was sent to Zoraxy.
+    Unchanged(Feed),
+}
+
+/// Fetch a feed and start importing it if it changed, recording the outcome on the feed.
+pub async fn refresh(ctx: &AppState, id: FeedId) -> Result<Refresh, Error> {
+    let feed = ctx.feeds.get(id).await.ok_or(Error::FeedNotFound(id))?;
+    // a list is only skipped as unchanged if every change of its last import went through,
+    // otherwise it is fetched and imported again in full
+    let last_import_ok = match feed.last_job_id {
+        Some(job_id) => ctx.jobs.get(job_id).await.is_some_and(|job| {
+            !matches!(job.state, JobState::Failed | JobState::Cancelled) && job.failed == 0
+        }),
+        None => false,
+    };
+    let result = fetch_and_import(ctx, &feed, last_import_ok).await;
+
+    ctx.feeds
+        .update(id, |feed| {
+            feed.last_checked_at = Some(unix_now());
+            match &result {
+                Ok(Refresh::Imported(started)) => {
+                    feed.last_error = None;
+                    feed.last_job_id = Some(started.job.id);
+                    feed.last_changed_at = feed.last_checked_at;
+                }
+                Ok(Refresh::Unchanged(_)) => feed.last_error = None,
+                Err(e) => feed.last_error = Some(e.to_string()),
+            }
+        })
+        .await;
+    match result? {
+        Refresh::Unchanged(_) => {
+            let feed = ctx.feeds.get(id).await.ok_or(Error::FeedNotFound(id))?;
+            Ok(Refresh::Unchanged(feed))
+        }
+        imported => Ok(imported),
+    }
+}
+
+async fn fetch_and_import(
+    ctx: &AppState,
+    feed: &Feed,
+    last_import_ok: bool,
+) -> Result<Refresh, Error> {
+    let fetched = async {
+        let fetched = fetch(
+            &ctx.reqwest_client,
+            feed,
+            last_import_ok,
+            &ctx.feeds.local_lists,
+        )
+        .await?;
+        match fetched {
+            Fetched::NotModified => Ok(None),
+            Fetched::Body {
+                list,
+                etag,
+                last_modified,
+            } => {
+                let text = list.into_text(&feed.url, &feed.options.members).await?;
+                Ok::<_, Error>(Some((text, etag, last_modified)))
+            }
+        }
+    };
+    // covers reading local lists and unzipping archives, which the request's timeout does not
+    let fetched = tokio::time::timeout(FETCH_TIMEOUT, fetched)
+        .await
+        .map_err(|_| {
+            Error::FeedFetch(format!(
+                "the list was not fetched within {}s",
+                FETCH_TIMEOUT.as_secs()
+            ))
+        })??;
+    let Some((text, etag, last_modified)) = fetched else {
+        tracing::debug!(feed_id = feed.id, "Feed not modified");
+        return Ok(Refresh::Unchanged(feed.clone()));
+    };
+
+    let list = parser::parse_list(&text, feed.format, &feed.options).map_err(Error::InvalidList)?;
+    let validated = import::screen_entries(ctx, list).await?;
+
+    let hash = content_hash(&validated.valid);
+    if last_import_ok && feed.content_hash.as_deref() == Some(hash.as_str()) {
+        tracing::debug!(feed_id = feed.id, "Feed content unchanged");
+        // the server didn't recognize its own validators, remember the new ones for next time
+        ctx.feeds
+            .update(feed.id, |feed| {
+                feed.etag = etag;
+                feed.last_modified = last_modified;
+            })
+            .await;
+        return Ok(Refresh::Unchanged(feed.clone()));
+    }
+
+    // scheduled refreshes give way to imports someone is waiting for
+    let started = import::queue_import(
+        ctx,
+        feed.access_rule_id.clone(),
+        feed.mode,
+        validated,
+        Some(feed.id),
+        Priority::Low,
+    )
+    .await;
+    // only remember what was fetched once it is queued for import, so a list that could not be
+    // imported is not mistaken for one that is already in Zoraxy
+    ctx.feeds
+        .update(feed.id, |feed| {
+            feed.etag = etag;
+            feed.last_modified = last_modified;
+            feed.content_hash = Some(hash);
+        })
+        .await;
+    Ok(Refresh::Imported(started))
+}
+
+/// The feeds to refresh at `now`: enabled feeds whose interval has passed, unless their last
+/// import has not finished yet.
+async fn due_feeds(ctx: &AppState, now: u64) -> Vec<Feed> {
+    let mut due = Vec::new();
+    for feed in ctx.feeds.list().await {
+        if !feed.is_due(now) {
+            continue;
+        }
+        // don't pile up imports of a feed behind one that has not finished yet
+        if let Some(job_id) = feed.last_job_id
+            && ctx
+                .jobs
+                .get(job_id)
+                .await
+                .is_some_and(|job| job.finished_at.is_none())
+        {
+            tracing::debug!(
+                feed_id = feed.id,
+                job_id,
+                "Last import of the feed has not finished, deferring refresh"
+            );
+            continue;
+        }
+        due.push(feed);
+    }
+    due
+}
+
+/// Refresh every enabled feed whenever its interval has passed, for as long as the plugin runs.
+///
+/// Up to [`MAX_REFRESHES`] feeds are refreshed side by side, so a slow host only holds up its own
+/// feed.
+pub async fn run_scheduler(ctx: AppState) {
+    let mut ticker = tokio::time::interval(SCHEDULER_TICK);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut refreshes = JoinSet::new();
+    let mut refreshing: HashMap<tokio::task::Id, FeedId> = HashMap::new();
+    loop {
+        ticker.tick().await;
+        while let Some(done) = refreshes.try_join_next_with_id() {
+            let id = match done {
+                Ok((id, ())) => id,
+                Err(e) => {
+                    tracing::error!(error = %e, "Feed refresh panicked");
+                    e.id()
+                }
+            };
+            refreshing.remove(&id);
+        }
+
+        for feed in due_feeds(&ctx, unix_now()).await {
+            if refreshes.len() >= MAX_REFRESHES {
+                break;
+            }
+            // the last refresh of the feed is still going
+            if refreshing.values().any(|id| *id == feed.id) {
+                continue;
+            }
+            let ctx = ctx.clone();
+            let task = refreshes.spawn(async move {
+                match refresh(&ctx, feed.id).await {
+                    Ok(Refresh::Imported(started)) => tracing::info!(
+                        feed_id = feed.id,
+                        job_id = started.job.id,
+                        "Refreshed feed {}",
+                        feed.url
+                    ),
+                    Ok(Refresh::Unchanged(_)) => {
+                        tracing::debug!(feed_id = feed.id, "Feed {} is unchanged", feed.url)
+                    }
+                    Err(e) => {
+                        tracing::warn!(feed_id = feed.id, error = %e, "Failed to refresh feed {}", feed.url)
+                    }
+                }
+            });
+            refreshing.insert(task.id(), feed.id);
+        }
+    }
+}
+
+#[debug_handler]
+pub async fn handle_list_feeds(State(state): State<AppState>) -> Json<Vec<Feed>> {
+    Json(state.feeds.list().await)
+}
+
+#[debug_handler]
+pub async fn handle_create_feed(
+    State(state): State<AppState>,
+    Json(new): Json<NewFeed>,
+) -> Result<Response, Error> {
+    new.validate(&state.feeds.local_lists)
+        .map_err(Error::InvalidFeed)?;
+    let feed = state.feeds.create(new).await;
+    tracing::info!(
+        feed_id = feed.id,
+        "Subscribed Access Rule ID: {} to {} every {}s",
+        feed.access_rule_id,
+        feed.url,
+        feed.interval_secs
+    );
+    Ok((StatusCode::CREATED, Json(feed)).into_response())
+}
+
+#[debug_handler]
+pub async fn handle_get_feed(
+    State(state): State<AppState>,
+    Path(id): Path<FeedId>,
+) -> Result<Json<Feed>, Error> {
+    state
+        .feeds
+        .get(id)
+        .await
+        .map(Json)
+        .ok_or(Error::FeedNotFound(id))
+}
+
+#[debug_handler]
+pub async fn handle_delete_feed(
+    State(state): State<AppState>,
+    Path(id): Path<FeedId>,
+) -> Result<StatusCode, Error> {
+    let feed = state
+        .feeds
+        .remove(id)
+        .await
+        .ok_or(Error::FeedNotFound(id))?;
+    tracing::info!(feed_id = id, "Unsubscribed from {}", feed.url);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Fetch and import a feed now, whether or not it is due or enabled.
+#[debug_handler]
+pub async fn handle_refresh_feed(
+    State(state): State<AppState>,
+    Path(id): Path<FeedId>,
+) -> Result<Response, Error> {
+    let refresh = refresh(&state, id).await?;
+    let status = match refresh {
+        Refresh::Imported(_) => StatusCode::ACCEPTED,
+        Refresh::Unchanged(_) => StatusCode::OK,
+    };
+    Ok((status, Json(refresh)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use axum::Router;
+    use axum::http::HeaderMap;
+    use axum::routing::get;
+
+    use super::*;
+
+    /// Serve `router` on a free local port, standing in for the host of a list.
+    async fn serve(router: Router) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+        addr
+    }
+
+    fn new_feed(url: &str) -> NewFeed {
+        NewFeed {
+            url: url.to_string(),
+            access_rule_id: "rule".to_string(),
+            mode: ImportMode::default(),
+            format: ListFormat::default(),
+            options: ListOptions::default(),
+            interval_secs: MIN_INTERVAL_SECS,
+            enabled: true,
+        }
+    }
+
+    fn feed(url: &str) -> Feed {
+        let new = new_feed(url);
+        Feed {
+            id: 1,
+            url: new.url,
+            access_rule_id: new.access_rule_id,
+            mode: new.mode,
+            format: new.format,
+            options: new.options,
+            interval_secs: new.interval_secs,
+            enabled: new.enabled,
+            created_at: 0,
+            last_checked_at: None,
+            last_error: None,
+            last_job_id: None,
+            last_changed_at: None,
+            etag: None,
+            last_modified: None,
+            content_hash: None,
+        }
+    }
+
+    /// The directory tests read local lists from.
+    fn local_lists() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("feed-lists-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Fetch `feed`, returning the text of the list.
+    async fn fetch_text(feed: &Feed) -> Result<String, Error> {
+        match fetch(&reqwest::Client::new(), feed, false, &local_lists()).await? {
+            Fetched::Body { list, .. } => list.into_text(&feed.url, &[]).await,
+            Fetched::NotModified => panic!("{} was not modified", feed.url),
+        }
+    }
+
+    #[test]
+    fn only_http_https_and_file_urls_are_accepted() {
+        let path = local_lists().join("accepted.txt");
+        std::fs::write(&path, "1.1.1.1\n").unwrap();
+        let file = Url::from_file_path(&path).unwrap();
+        for url in [
+            "http://example.com/list.txt",
+            "https://example.com/list.txt",
+            file.as_str(),
+        ] {
+            assert_eq!(new_feed(url).validate(&local_lists()), Ok(()), "{url}");
+        }
+        for url in ["ftp://example.com/list.txt", "example.com/list.txt", ""] {
+            assert!(
+                new_feed(url).validate(&local_lists()).is_err(),
+                "{url} should be rejected"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn local_files_outside_the_lists_directory_are_refused() {
+        let outside = std::env::temp_dir().join(format!("feed-outside-{}.txt", std::process::id()));
+        std::fs::write(&outside, "1.1.1.1\n").unwrap();
+        let escaped = local_lists().join("..").join(outside.file_name().unwrap());
+        let linked = local_lists().join("linked.txt");
+        let _ = std::fs::remove_file(&linked);
+        std::os::unix::fs::symlink(&outside, &linked).unwrap();
+
+        for path in [outside.as_path(), &escaped, &linked, &local_lists()] {
+            let url = Url::from_file_path(path).unwrap();
+            assert!(
+                new_feed(url.as_str()).validate(&local_lists()).is_err(),
+                "{url} should be rejected"
+            );
+            let error = fetch_text(&feed(url.as_str())).await;
+            assert!(
+                matches!(&error, Err(Error::FeedFetch(e)) if e.contains("not a file in")),
+                "{error:?}"
+            );
+        }
+        let etc = Url::from_file_path("/etc/passwd").unwrap();
+        assert!(fetch_text(&feed(etc.as_str())).await.is_err());
+    }
+
+    #[test]
+    fn feeds_need_an_access_rule_and_a_long_enough_interval() {
+        let mut feed = new_feed("https://example.com/list.txt");
+        feed.access_rule_id = " ".to_string();
+        assert!(feed.validate(&local_lists()).is_err());
+
+        let mut feed = new_feed("https://example.com/list.txt");
+        feed.interval_secs = MIN_INTERVAL_SECS - 1;
+        assert!(feed.validate(&local_lists()).is_err());
+    }
+
+    #[tokio::test]
+    async fn lists_are_fetched_over_http() {
+        let router = Router::new()
+            .route(
+                "/list.txt",
+                get(|| async { "1.1.1.1\n# comment\n2.2.2.0/24\n" }),
+            )
+            .route(
+                "/missing.txt",
+                get(|| async { (StatusCode::NOT_FOUND, "not here") }),
+            );
+        let addr = serve(router).await;
+
+        let text = fetch_text(&feed(&format!("http://{addr}/list.txt"))).await;
+        assert_eq!(text.unwrap(), "1.1.1.1\n# comment\n2.2.2.0/24\n");
+
+        let error = fetch_text(&feed(&format!("http://{addr}/missing.txt"))).await;
+        assert!(
+            matches!(&error, Err(Error::FeedFetch(e)) if e.contains("404")),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_are_read_from_local_files() {
+        let path = local_lists().join("local.txt");
+        std::fs::write(&path, "1.1.1.1\n").unwrap();
+        let url = Url::from_file_path(&path).unwrap();
+        assert_eq!(fetch_text(&feed(url.as_str())).await.unwrap(), "1.1.1.1\n");
+
+        std::fs::remove_file(&path).unwrap();
+        let error = fetch_text(&feed(url.as_str())).await;
+        assert!(matches!(error, Err(Error::FeedFetch(_))), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn local_files_larger_than_an_upload_are_refused() {
+        let path = local_lists().join("large.txt");
+        let file = std::fs::File::create(&path).unwrap();
+        file.set_len(crate::MAX_IMPORT_BODY_SIZE as u64 + 1)
+            .unwrap();
+        let url = Url::from_file_path(&path).unwrap();
+
+        let error = fetch_text(&feed(url.as_str())).await;
+        std::fs::remove_file(&path).unwrap();
+        assert!(
+            matches!(&error, Err(Error::FeedFetch(e)) if *e == too_large()),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn due_feeds_wait_for_their_interval_and_their_last_import() {
+        let ctx = AppState::for_tests("feeds-due");
+        let due = ctx.feeds.create(new_feed("https://example.com/a")).await;
+        let disabled = ctx
+            .feeds
+            .create(NewFeed {
+                enabled: false,
+                ..new_feed("https://example.com/b")
+            })
+            .await;
+        let checked = ctx.feeds.create(new_feed("https://example.com/c")).await;
+        ctx.feeds
+            .update(checked.id, |feed| feed.last_checked_at = Some(1000))
+            .await;
+        let importing = ctx.feeds.create(new_feed("https://example.com/d")).await;
+        let job = ctx
+            .jobs
+            .create(
+                "rule".to_string(),
+                ImportMode::default(),
+                &validate::Validated::default(),
+                Some(importing.id),
+                Priority::Low,
+            )
+            .await;
+        ctx.feeds
+            .update(importing.id, |feed| feed.last_job_id = Some(job.id))
+            .await;
+
+        let ids = |feeds: Vec<Feed>| feeds.iter().map(|feed| feed.id).collect::<Vec<_>>();
+        let now = 1000 + MIN_INTERVAL_SECS - 1;
+        assert_eq!(ids(due_feeds(&ctx, now).await), [due.id]);
+        let now = 1000 + MIN_INTERVAL_SECS;
+        assert_eq!(ids(due_feeds(&ctx, now).await), [due.id, checked.id]);
+
+        ctx.jobs
+            .mark_finished(job.id, JobState::Succeeded, None)
+            .await;
+        assert_eq!(
+            ids(due_feeds(&ctx, now).await),
+            [due.id, checked.id, importing.id]
+        );
+        assert!(!disabled.is_due(now));
+    }
+
+    #[test]
+    fn content_hash_ignores_order_duplicates_and_notation() {
+        let nets = |texts: &[&str]| -> Vec<ipnet::IpNet> {
+            texts.iter().map(|text| text.parse().unwrap()).collect()
+        };
+        let hash = content_hash(&nets(&["2001:db8::/32", "1.1.1.1/32", "10.0.0.0/8"]));
+        assert_eq!(
+            hash,
+            "9767aec9693831c9d8551a1427b3b367b80a5fc400733259e73c9efa55598d8f"
+        );
+        assert_eq!(
+            content_hash(&nets(&[
+                "10.0.0.0/8",
+                "1.1.1.1/32",
+                "2001:DB8::/32",
+                "1.1.1.1/32"
+            ])),
+            hash
+        );
+        assert_ne!(content_hash(&nets(&["1.1.1.1/32"])), hash);
+    }
+
+    /// Host of a list that answers with `ETag` and `Last-Modified` validators.
+    #[derive(Clone, Default)]
+    struct ListHost {
+        /// Headers of each request the host got.
+        requests: Arc<Mutex<Vec<HeaderMap>>>,
+        /// Whether to answer a request with matching validators with `304 Not Modified`.
+        honors_validators: Arc<AtomicBool>,
+    }
+
+    const ETAG: &str = "\"v1\"";
+    const LAST_MODIFIED: &str = "Wed, 14 Oct 2026 00:00:00 GMT";
+
+    impl ListHost {
+        async fn serve(&self) -> String {
+            let host = self.clone();
+            let router = Router::new().route(
+                "/list.txt",
+                get(move |headers: HeaderMap| async move {
+                    let matches = headers
+                        .get(header::IF_NONE_MATCH)
+                        .is_some_and(|etag| etag == ETAG);
+                    host.requests.lock().unwrap().push(headers);
+                    if matches && host.honors_validators.load(Ordering::Relaxed) {
+                        return StatusCode::NOT_MODIFIED.into_response();
+                    }
+                    let validators = [(header::ETAG, ETAG), (header::LAST_MODIFIED, LAST_MODIFIED)];
+                    (validators, "1.1.1.1\n2.2.2.0/24\n").into_response()
+                }),
+            );
+            format!("http://{}/list.txt", serve(router).await)
+        }
+
+        /// The validators sent with the last request.
+        fn last_validators(&self) -> (Option<String>, Option<String>) {
+            let requests = self.requests.lock().unwrap();
+            let headers = requests.last().expect("the host was asked for the list");
+            let value = |name| {
+                headers
+                    .get(name)
+                    .map(|v: &header::HeaderValue| v.to_str().unwrap().to_string())
+            };
+            (
+                value(header::IF_NONE_MATCH),
+                value(header::IF_MODIFIED_SINCE),
+            )
+        }
+    }
+
+    /// Subscribe to the list of `host`, returning the feed's ID.
+    async fn subscribe(ctx: &AppState, host: &ListHost) -> FeedId {
+        let url = host.serve().await;
+        ctx.feeds.create(new_feed(&url)).await.id
+    }
+
+    /// Refresh a feed, expecting it to start an import, and finish the import as `state`.
+    async fn import(ctx: &AppState, id: FeedId, state: JobState) {
+        let job = match refresh(ctx, id).await.unwrap() {
+            Refresh::Imported(started) => started.job,
+            Refresh::Unchanged(_) => panic!("feed {id} was not imported"),
+        };
+        ctx.jobs.mark_finished(job.id, state, None).await;
+    }
+
+    #[tokio::test]
+    async fn validators_are_sent_only_after_a_successful_import() {
+        let ctx = AppState::for_tests("feeds-conditional");
+        let host = ListHost::default();
+        let id = subscribe(&ctx, &host).await;
+
+        import(&ctx, id, JobState::Failed).await;
+        assert_eq!(host.last_validators(), (None, None));
+        let feed = ctx.feeds.get(id).await.unwrap();
+        assert_eq!(feed.etag.as_deref(), Some(ETAG));
+        assert_eq!(feed.last_modified.as_deref(), Some(LAST_MODIFIED));
+
+        // the failed import is fetched and imported again in full
+        import(&ctx, id, JobState::Succeeded).await;
+        assert_eq!(host.last_validators(), (None, None));
+
+        assert!(matches!(refresh(&ctx, id).await, Ok(Refresh::Unchanged(_))));
+        assert_eq!(
+            host.last_validators(),
+            (Some(ETAG.to_string()), Some(LAST_MODIFIED.to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn a_list_that_was_not_modified_is_unchanged() {
+        let ctx = AppState::for_tests("feeds-not-modified");
+        let host = ListHost::default();
+        host.honors_validators.store(true, Ordering::Relaxed);
+        let id = subscribe(&ctx, &host).await;
+        import(&ctx, id, JobState::Succeeded).await;
+
+        let jobs = ctx.jobs.list().await.len();
+        let refreshed = refresh(&ctx, id).await;
+        assert!(
+            matches!(refreshed, Ok(Refresh::Unchanged(_))),
+            "{refreshed:?}"
+        );
+        // the host answered `304 Not Modified`, as it got its own validators back
+        assert_eq!(host.last_validators().0.as_deref(), Some(ETAG));
+        assert_eq!(ctx.jobs.list().await.len(), jobs);
+        let feed = ctx.feeds.get(id).await.unwrap();
+        assert!(feed.last_checked_at.is_some());
+        assert!(feed.last_error.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_list_with_the_same_entries_is_not_imported_again() {
+        let ctx = AppState::for_tests("feeds-same-hash");
+        // the host sends the whole list whatever the validators say
+        let host = ListHost::default();
+        let id = subscribe(&ctx, &host).await;
+        import(&ctx, id, JobState::Succeeded).await;
+        let hash = ctx.feeds.get(id).await.unwrap().content_hash;
+        assert!(hash.is_some());
+
+        let jobs = ctx.jobs.list().await.len();
+        let refreshed = refresh(&ctx, id).await;
+        assert!(
+            matches!(refreshed, Ok(Refresh::Unchanged(_))),
+            "{refreshed:?}"
+        );
+        assert_eq!(ctx.jobs.list().await.len(), jobs);
+        assert_eq!(ctx.feeds.get(id).await.unwrap().content_hash, hash);
+    }
+
+    #[tokio::test]
+    async fn a_list_whose_import_had_failures_is_imported_again() {
+        let ctx = AppState::for_tests("feeds-failed-entries");
+        let host = ListHost::default();
+        host.honors_validators.store(true, Ordering::Relaxed);
+        let id = subscribe(&ctx, &host).await;
+        import(&ctx, id, JobState::Succeeded).await;
+
+        // one of the entries did not make it into Zoraxy
+        let job_id = ctx.feeds.get(id).await.unwrap().last_job_id.unwrap();
+        ctx.jobs.update(job_id, |job| job.failed = 1).await;
+        import(&ctx, id, JobState::Succeeded).await;
+        assert_eq!(host.last_validators(), (None, None));
+        assert_ne!(ctx.feeds.get(id).await.unwrap().last_job_id, Some(job_id));
+    }
+}
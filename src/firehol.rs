@@ -0,0 +1,157 @@
+//! FireHOL `.netset` and `.ipset` files: a `#` comment header describing the list, then one IP or
+//! network per line.
+
+use crate::parser::{ListFormat, ListMetadata};
+
+/// Whether `input` starts with a FireHOL header.
+pub fn detect(input: &str) -> bool {
+    header(input).any(|line| {
+        line.contains(" hash:net")
+            || line.contains(" hash:ip")
+            || field(line, "Maintainer").is_some()
+    })
+}
+
+/// What the header of a FireHOL file says about the list.
+pub fn parse_header(input: &str) -> ListMetadata {
+    let mut metadata = ListMetadata {
+        format: ListFormat::Firehol,
+        ..ListMetadata::default()
+    };
+    let mut description = Vec::new();
+    // the description runs from the set type line to the first field
+    let mut in_description = false;
+    for line in header(input).filter(|line| !line.is_empty()) {
+        if metadata.name.is_none() {
+            metadata.name = Some(line.to_string());
+            continue;
+        }
+        if line.contains(" hash:") {
+            in_description = true;
+            continue;
+        }
+        let fields: [(&str, &mut Option<String>); 7] = [
+            ("Maintainer", &mut metadata.maintainer),
+            ("Maintainer URL", &mut metadata.maintainer_url),
+            ("List source URL", &mut metadata.source_url),
+            ("Category", &mut metadata.category),
+            ("Version", &mut metadata.version),
+            ("This File Date", &mut metadata.date),
+            ("Entries", &mut metadata.entries),
+        ];
+        let known = fields
+            .into_iter()
+            .find_map(|(name, slot)| Some((field(line, name)?, slot)));
+        match known {
+            Some((value, slot)) => {
+                in_description = false;
+                if !value.is_empty() {
+                    *slot = Some(value.to_string());
+                }
+            }
+            None if in_description => description.push(line),
+            None => {}
+        }
+    }
+    if !description.is_empty() {
+        metadata.description = Some(description.join(" "));
+    }
+    metadata
+}
+
+/// The comment lines at the top of `input`, without their `#`.
+fn header(input: &str) -> impl Iterator<Item = &str> {
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+    input
+        .lines()
+        .map(str::trim)
+        .take_while(|line| line.is_empty() || line.starts_with('#'))
+        .map(|line| line.trim_start_matches('#').trim())
+}
+
+/// The value of a `Name : value` header line, if `line` is one for `name`.
+fn field<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let (key, value) = line.split_once(':')?;
+    (key.trim() == name).then(|| value.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NETSET: &str = "\
+#
+# firehol_level1
+#
+# ipv4 hash:net ipset
+#
+# A firewall blacklist composed from IP lists, providing
+# maximum protection with minimum false positives.
+#
+# Maintainer      : FireHOL
+# Maintainer URL  : http://iplists.firehol.org/
+# List source URL : 
+# Source File Date: Mon Jan  1 00:00:00 UTC 2024
+#
+# Category        : attacks
+# Version         : 12345
+#
+# This File Date  : Mon Jan  1 00:10:00 UTC 2024
+# Update Frequency: 1 min
+# Entries         : 4521 subnets, 610000000 unique IPs
+#
+1.10.16.0/20
+1.19.0.0/16
+";
+
+    #[test]
+    fn detects_the_header() {
+        assert!(detect(NETSET));
+        assert!(detect("# blocklist_de\n#\n# ipv4 hash:ip ipset\n1.2.3.4\n"));
+        assert!(!detect("# my list\n1.2.3.4\n"));
+        assert!(!detect("1.2.3.4\n# ipv4 hash:net ipset\n"));
+    }
+
+    #[test]
+    fn reads_the_header_fields() {
+        let metadata = parse_header(NETSET);
+        assert_eq!(metadata.format, ListFormat::Firehol);
+        assert_eq!(metadata.name.as_deref(), Some("firehol_level1"));
+        assert_eq!(
+            metadata.description.as_deref(),
+            Some(
+                "A firewall blacklist composed from IP lists, providing maximum protection with \
+                 minimum false positives."
+            )
+        );
+        assert_eq!(metadata.maintainer.as_deref(), Some("FireHOL"));
+        assert_eq!(
+            metadata.maintainer_url.as_deref(),
+            Some("http://iplists.firehol.org/")
+        );
+        // empty fields are left unset
+        assert_eq!(metadata.source_url, None);
+        assert_eq!(metadata.category.as_deref(), Some("attacks"));
+        assert_eq!(metadata.version.as_deref(), Some("12345"));
+        assert_eq!(
+            metadata.date.as_deref(),
+            Some("Mon Jan  1 00:10:00 UTC 2024")
+        );
+        assert_eq!(
+            metadata.entries.as_deref(),
+            Some("4521 subnets, 610000000 unique IPs")
+        );
+    }
+
+    #[test]
+    fn values_may_contain_colons() {
+        assert_eq!(
+            field(
+                "Maintainer URL  : http://example.com:8080/",
+                "Maintainer URL"
+            ),
+            Some("http://example.com:8080/")
+        );
+        assert_eq!(field("Maintainer URL : x", "Maintainer"), None);
+    }
+}
@@ -0,0 +1,780 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ipnet::IpNet;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+use crate::errors::Error;
+use crate::events::JobEvent;
+use crate::feeds::FeedId;
+use crate::jobs::{Job, JobId, JobState, unix_now};
+use crate::parser::ParsedList;
+use crate::plan::{Action, Change, ImportMode, plan_import};
+use crate::queue::Priority;
+use crate::retry::backoff_delay;
+use crate::safelist::Safelist;
+use crate::settings::Settings;
+use crate::validate::{Validated, parse_entry, validate_entries};
+use crate::zoraxy_client::{ApiFailure, FailureKind};
+use crate::{AppState, ImportStarted, rollback};
+
+/// Outcome of trying to apply a single change to Zoraxy.
+enum ChangeOutcome {
+    Applied,
+    Failed(ApiFailure),
+    /// The change failed in a way that means the rest of the import would fail too.
+    Abort(ApiFailure),
+    /// The job was cancelled before the change was sent.
+    Cancelled,
+}
+
+/// Shared state of one import, handed to each of its workers.
+struct ImportRun {
+    ctx: AppState,
+    settings: Settings,
+    job_id: JobId,
+    feed_id: Option<FeedId>,
+    access_rule_id: String,
+    /// The changes left to make, with their index in the job's plan.
+    changes: Vec<(usize, Change)>,
+    /// Number of changes in the job's plan, including those made before a restart.
+    total: usize,
+    /// Position in `changes` of the next change to be picked up by a worker.
+    next: AtomicUsize,
+    aborted: Mutex<Option<ApiFailure>>,
+    cancelled: watch::Receiver<bool>,
+    /// The list's own references for the job's entries, see [`ImportRun::reference`].
+    references: BTreeMap<String, String>,
+    /// When this run started and how many changes it has made, to measure its throughput.
+    started: Instant,
+    done: AtomicUsize,
+}
+
+/// Validate the entries of a list and hold them against the safelist.
+///
+/// Fails if nothing is left to import, rather than starting a job that does nothing, or that
+/// would empty the Access Rule in [`ImportMode::Sync`].
+pub async fn screen_entries(ctx: &AppState, list: ParsedList) -> Result<Validated, Error> {
+    let mut validated = validate_entries(list.entries);
+    validated.invalid.extend(list.invalid);
+    validated.invalid.sort_by_key(|entry| entry.line);
+    validated.filtered = list.filtered;
+    validated.metadata = list.metadata;
+    if validated.valid.is_empty() {
+        return Err(Error::NoValidEntries(validated.invalid));
+    }
+
+    let safelist = Safelist::new(&ctx.settings.read().await.safelist);
+    safelist.screen(&mut validated);
+    if validated.valid.is_empty() {
+        return Err(Error::OnlySafelistedEntries(validated.conflicts));
+    }
+    if !validated.conflicts.is_empty() {
+        tracing::warn!(
+            conflicts = validated.conflicts.len(),
+            "Held back entries that overlap the safelist"
+        );
+    }
+    Ok(validated)
+}
+
+/// Create a job importing the valid entries of a list into an Access Rule and queue it to run in
+/// the background.
+///
+/// `feed_id` is the feed subscription the list was fetched from, if any.
+pub async fn queue_import(
+    ctx: &AppState,
+    access_rule_id: String,
+    mode: ImportMode,
+    validated: Validated,
+    feed_id: Option<FeedId>,
+    priority: Priority,
+) -> ImportStarted {
+    let job = ctx
+        .jobs
+        .create(access_rule_id.clone(), mode, &validated, feed_id, priority)
+        .await;
+    ctx.queue.push(&job);
+    tracing::info!(
+        job_id = job.id,
+        "Queued import of {} IPs to Access Rule ID: {}",
+        validated.valid.len(),
+        access_rule_id
+    );
+
+    ImportStarted {
+        job,
+        invalid_entries: validated.invalid,
+        safelisted_entries: validated.conflicts,
+    }
+}
+
+/// Run a job the queue has picked, carrying on from its checkpoint if it was interrupted.
+///
+/// Jobs that had planned their changes carry on with the ones they had not sent yet, jobs that
+/// had not are started from the beginning. A change that was in flight when the plugin stopped is
+/// sent again. Jobs saved without a checkpoint can't be run and are failed.
+pub async fn run_job(ctx: &AppState, job_id: JobId) {
+    let Some(job) = ctx.jobs.get(job_id).await else {
+        return;
+    };
+    if job.finished_at.is_some() {
+        return;
+    }
+    let Some(checkpoint) = ctx.jobs.restore(job_id).await else {
+        ctx.jobs
+            .mark_finished(
+                job_id,
+                JobState::Failed,
+                Some("Interrupted by a restart of the plugin".to_string()),
+            )
+            .await;
+        return;
+    };
+
+    match checkpoint.changes {
+        Some(_) => {
+            let remaining = checkpoint.remaining();
+            tracing::info!(
+                job_id,
+                remaining = remaining.len(),
+                "Resuming import interrupted by a restart"
+            );
+            ctx.jobs.mark_resumed(job_id).await;
+            let settings = ctx.settings.read().await.clone();
+            apply_changes(ctx, settings, job_id, &job.access_rule_id, remaining).await;
+        }
+        None if job.rollback_of.is_some() => rollback::run_rollback(ctx, job).await,
+        None => {
+            let entries = checkpoint
+                .entries
+                .iter()
+                .filter_map(|entry| parse_entry(entry).ok())
+                .collect();
+            run_import(ctx, job_id, job.access_rule_id, entries, job.mode).await;
+        }
+    }
+}
+
+/// Import `entries` into the Access Rule of job `job_id`, recording progress in the job registry.
+///
+/// Only entries the Access Rule does not already have are sent to Zoraxy. In
+/// [`ImportMode::Sync`], entries of the Access Rule that are not in `entries` are removed.
+async fn run_import(
+    ctx: &AppState,
+    job_id: JobId,
+    access_rule_id: String,
+    entries: Vec<IpNet>,
+    mode: ImportMode,
+) {
+    ctx.jobs.mark_running(job_id).await;
+    // settings changes apply to the next import, not half way through this one
+    let settings = ctx.settings.read().await.clone();
+
+    let existing = match ctx.zoraxy.list_blacklisted_ips(&access_rule_id).await {
+        Ok(existing) => existing,
+        Err(failure) => {
+            tracing::error!(job_id, error = %failure.message, "Failed to read current blacklist");
+            ctx.jobs
+                .mark_finished(
+                    job_id,
+                    JobState::Failed,
+                    Some(format!(
+                        "Could not read the Access Rule's current blacklist: {}",
+                        failure.message
+                    )),
+                )
+                .await;
+            return;
+        }
+    };
+    ctx.provenance.retain(&access_rule_id, &existing).await;
+    let plan = plan_import(&entries, &existing, mode);
+    let changes = plan.changes();
+    ctx.jobs
+        .update(job_id, |job| {
+            job.duplicates = plan.duplicates.len();
+            job.aggregated = plan.aggregated.len();
+            job.already_present = plan.already_present.len();
+            job.to_remove = plan.to_remove.len();
+            job.total = changes.len();
+        })
+        .await;
+    ctx.jobs.checkpoint_plan(job_id, &changes).await;
+    tracing::info!(
+        job_id,
+        new = plan.to_add.len(),
+        stale = plan.to_remove.len(),
+        already_present = plan.already_present.len(),
+        duplicates = plan.duplicates.len(),
+        aggregated = plan.aggregated.len(),
+        "Compared blocklist against Access Rule ID: {access_rule_id}"
+    );
+
+    let changes = changes.into_iter().enumerate().collect();
+    apply_changes(ctx, settings, job_id, &access_rule_id, changes).await;
+}
+
+/// Queue the jobs that were still going when the plugin last stopped, oldest first.
+pub async fn resume_interrupted(ctx: &AppState) {
+    let mut interrupted: Vec<Job> = ctx
+        .jobs
+        .list()
+        .await
+        .into_iter()
+        .filter(|job| job.finished_at.is_none())
+        .collect();
+    interrupted.reverse();
+
+    for job in interrupted {
+        ctx.jobs
+            .update(job.id, |job| job.state = JobState::Queued)
+            .await;
+        ctx.queue.push(&job);
+    }
+}
+
+/// Send `changes` to Zoraxy with a pool of workers, then settle the job's final state.
+pub async fn apply_changes(
+    ctx: &AppState,
+    settings: Settings,
+    job_id: JobId,
+    access_rule_id: &str,
+    changes: Vec<(usize, Change)>,
+) {
+    let workers = settings.throughput.workers.clamp(1, changes.len().max(1));
+    let job = ctx.jobs.get(job_id).await;
+    let run = Arc::new(ImportRun {
+        ctx: ctx.clone(),
+        settings,
+        job_id,
+        feed_id: job.as_ref().and_then(|job| job.feed_id),
+        access_rule_id: access_rule_id.to_string(),
+        changes,
+        total: job.map_or(0, |job| job.total),
+        next: AtomicUsize::new(0),
+        aborted: Mutex::new(None),
+        cancelled: ctx.jobs.cancellation(job_id),
+        references: ctx.jobs.references(job_id).await,
+        started: Instant::now(),
+        done: AtomicUsize::new(0),
+    });
+
+    let mut pool = JoinSet::new();
+    for _ in 0..workers {
+        pool.spawn(Arc::clone(&run).work());
+    }
+    while let Some(result) = pool.join_next().await {
+        if let Err(e) = result {
+            tracing::error!(job_id, error = %e, "Import worker panicked");
+        }
+    }
+
+    let aborted = run.aborted.lock().expect("abort lock poisoned").take();
+    // counted over the whole job, a resumed job may have made changes before the restart
+    let job = ctx.jobs.get(job_id).await;
+    let nothing_applied = job
+        .as_ref()
+        .is_some_and(|job| job.succeeded == 0 && job.total > 0);
+    if *run.cancelled.borrow() {
+        let (processed, total) = job.map_or((0, 0), |job| (job.processed, job.total));
+        ctx.jobs
+            .mark_finished(
+                job_id,
+                JobState::Cancelled,
+                Some(format!("Cancelled after {processed} of {total} changes")),
+            )
+            .await;
+    } else if let Some(cause) = aborted {
+        let reason = match cause.kind {
+            FailureKind::AuthFailure => {
+                format!("Zoraxy rejected the plugin's API key: {}", cause.message)
+            }
+            _ => cause.message,
+        };
+        ctx.jobs
+            .mark_finished(job_id, JobState::Failed, Some(reason))
+            .await;
+    } else if nothing_applied {
+        // a job where nothing landed is a failure, even if each IP failed for its own reason
+        ctx.jobs
+            .mark_finished(
+                job_id,
+                JobState::Failed,
+                Some("None of the changes could be applied".to_string()),
+            )
+            .await;
+    } else {
+        ctx.jobs
+            .mark_finished(job_id, JobState::Succeeded, None)
+            .await;
+    }
+    tracing::info!(job_id, access_rule_id = %access_rule_id, "Import finished");
+}
+
+impl ImportRun {
+    /// Worker loop: take the next change off the list until none are left.
+    async fn work(self: Arc<Self>) {
+        let job_id = self.job_id;
+        let access_rule_id = &self.access_rule_id;
+        let mut cancelled = self.cancelled.clone();
+
+        loop {
+            // changes left over when the job is cancelled are neither sent nor reported
+            if *cancelled.borrow() {
+                return;
+            }
+
+            let next = self.next.fetch_add(1, Ordering::Relaxed);
+            let Some((index, change)) = self.changes.get(next) else {
+                return;
+            };
+
+            // once the import has been aborted every other request would fail the same way,
+            // so the rest of the list is recorded as failed without being sent.
+            let cause = self.aborted.lock().expect("abort lock poisoned").clone();
+            if let Some(cause) = cause {
+                let failure = ApiFailure {
+                    kind: cause.kind,
+                    status: None,
+                    message: format!("Not attempted, import aborted: {}", cause.message),
+                };
+                self.record(*index, change, Err(failure)).await;
+                continue;
+            }
+
+            tracing::debug!(
+                job_id,
+                "Applying change {}/{} ({} {}) to Access Rule ID: {}",
+                index + 1,
+                self.total,
+                change.action.as_str(),
+                change.entry,
+                access_rule_id
+            );
+
+            let outcome = apply_with_retry(
+                &self.ctx,
+                &self.settings,
+                job_id,
+                change,
+                &mut cancelled,
+                || self.ctx.zoraxy.apply(access_rule_id, change),
+            )
+            .await;
+            let result = match outcome {
+                ChangeOutcome::Applied => {
+                    self.ctx
+                        .provenance
+                        .record(
+                            access_rule_id,
+                            change,
+                            job_id,
+                            self.feed_id,
+                            self.reference(change),
+                        )
+                        .await;
+                    Ok(())
+                }
+                ChangeOutcome::Failed(failure) => {
+                    tracing::warn!(
+                        job_id,
+                        access_rule_id = %access_rule_id,
+                        action = change.action.as_str(),
+                        entry = %change.entry,
+                        kind = failure.kind.as_str(),
+                        status = ?failure.status,
+                        error = %failure.message,
+                        "Failed to apply change to Access Rule"
+                    );
+                    Err(failure)
+                }
+                ChangeOutcome::Abort(failure) => {
+                    tracing::error!(
+                        job_id,
+                        access_rule_id = %access_rule_id,
+                        kind = failure.kind.as_str(),
+                        error = %failure.message,
+                        "Aborting import"
+                    );
+                    self.aborted
+                        .lock()
+                        .expect("abort lock poisoned")
+                        .get_or_insert_with(|| failure.clone());
+                    Err(failure)
+                }
+                ChangeOutcome::Cancelled => return,
+            };
+            self.record(*index, change, result).await;
+        }
+    }
+
+    /// The list's reference for an added entry. An entry the list's networks were merged into
+    /// gets the references of all of them.
+    fn reference(&self, change: &Change) -> Option<String> {
+        if change.action != Action::Add || self.references.is_empty() {
+            return None;
+        }
+        if let Some(reference) = self.references.get(&change.entry) {
+            return Some(reference.clone());
+        }
+        let net = parse_entry(&change.entry).ok()?;
+        let mut merged: Vec<&str> = self
+            .references
+            .iter()
+            .filter(|(entry, _)| parse_entry(entry).is_ok_and(|entry| net.contains(&entry)))
+            .map(|(_, reference)| reference.as_str())
+            .collect();
+        merged.sort_unstable();
+        merged.dedup();
+        (!merged.is_empty()).then(|| merged.join(", "))
+    }
+
+    /// Record the outcome of a change and tell the UI about it.
+    async fn record(&self, index: usize, change: &Change, result: Result<(), ApiFailure>) {
+        let error = result.as_ref().err().map(|failure| failure.message.clone());
+        let Some(job) = self
+            .ctx
+            .jobs
+            .record_result(self.job_id, index, change, result)
+            .await
+        else {
+            return;
+        };
+
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let throughput = if elapsed > 0.0 {
+            done as f64 / elapsed
+        } else {
+            0.0
+        };
+        let remaining = job.total.saturating_sub(job.processed);
+        let eta_secs = (throughput > 0.0).then(|| (remaining as f64 / throughput).ceil() as u64);
+        self.ctx.jobs.publish(JobEvent::Progress {
+            job,
+            index,
+            change: change.clone(),
+            error,
+            throughput,
+            eta_secs,
+        });
+    }
+}
+
+/// Wait for `wait` to complete, unless the job is cancelled first. Returns whether it was.
+async fn cancellable(
+    cancelled: &mut watch::Receiver<bool>,
+    wait: impl Future<Output = ()>,
+) -> bool {
+    tokio::select! {
+        () = wait => false,
+        // an error means the signal is gone, the branch is then disabled and `wait` runs out
+        Ok(_) = cancelled.wait_for(|cancelled| *cancelled) => true,
+    }
+}
+
+/// Apply a single change with `send`, retrying transient failures and pausing while the circuit
+/// breaker is open.
+///
+/// Cancelling the job interrupts the waits in between attempts, never a request in flight.
+async fn apply_with_retry<F>(
+    ctx: &AppState,
+    settings: &Settings,
+    job_id: JobId,
+    change: &Change,
+    cancelled: &mut watch::Receiver<bool>,
+    send: impl Fn() -> F,
+) -> ChangeOutcome
+where
+    F: Future<Output = Result<(), ApiFailure>>,
+{
+    let max_pause = Duration::from_secs(settings.circuit_breaker.max_pause_secs);
+    let mut paused_since: Option<Instant> = None;
+
+    loop {
+        // wait out an open circuit, resuming with a fresh set of attempts afterwards
+        if let Some(until) = ctx.breaker.open_until() {
+            let since = *paused_since.get_or_insert_with(Instant::now);
+            if until.duration_since(since) > max_pause {
+                return ChangeOutcome::Abort(ApiFailure {
+                    kind: FailureKind::Transport,
+                    status: None,
+                    message: format!(
+                        "Zoraxy has been unavailable for more than {} seconds",
+                        max_pause.as_secs()
+                    ),
+                });
+            }
+
+            let remaining = until.saturating_duration_since(Instant::now());
+            tracing::warn!(
+                job_id,
+                "Zoraxy appears to be down, pausing import for {}s",
+                remaining.as_secs()
+            );
+            ctx.jobs
+                .mark_paused(job_id, unix_now() + remaining.as_secs())
+                .await;
+            if cancellable(cancelled, tokio::time::sleep_until(until)).await {
+                return ChangeOutcome::Cancelled;
+            }
+            ctx.jobs.mark_resumed(job_id).await;
+        }
+
+        let mut attempt = 1;
+        let failure = loop {
+            if cancellable(cancelled, ctx.rate_limiter.acquire()).await {
+                return ChangeOutcome::Cancelled;
+            }
+            let failure = match send().await {
+                Ok(()) => {
+                    ctx.breaker.record_success();
+                    return ChangeOutcome::Applied;
+                }
+                Err(failure) => failure,
+            };
+
+            if !failure.is_transient() {
+                // Zoraxy is up and answering, it just didn't like this request
+                ctx.breaker.record_success();
+                return match failure.kind {
+                    FailureKind::AuthFailure => ChangeOutcome::Abort(failure),
+                    _ => ChangeOutcome::Failed(failure),
+                };
+            }
+
+            if attempt >= settings.retry.max_attempts {
+                // the change counts against the circuit once, after its last attempt
+                ctx.breaker.record_failure(&settings.circuit_breaker);
+                break failure;
+            }
+            // another change opened the circuit, wait it out rather than retry into it
+            if ctx.breaker.open_until().is_some() {
+                break failure;
+            }
+
+            let delay = backoff_delay(&settings.retry, attempt);
+            tracing::debug!(
+                job_id,
+                entry = %change.entry,
+                attempt,
+                error = %failure.message,
+                "Transient failure, retrying in {}ms",
+                delay.as_millis()
+            );
+            if cancellable(cancelled, tokio::time::sleep(delay)).await {
+                return ChangeOutcome::Cancelled;
+            }
+            attempt += 1;
+        };
+
+        if ctx.breaker.open_until().is_none() {
+            return ChangeOutcome::Failed(failure);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::settings::CircuitBreakerSettings;
+
+    fn change() -> Change {
+        Change {
+            action: Action::Add,
+            entry: "1.1.1.1/32".to_string(),
+        }
+    }
+
+    fn failure(kind: FailureKind, status: u16) -> ApiFailure {
+        ApiFailure {
+            kind,
+            status: Some(status),
+            message: format!("{status}"),
+        }
+    }
+
+    fn settings(max_attempts: u32, breaker: CircuitBreakerSettings) -> Settings {
+        let mut settings = Settings::default();
+        settings.retry.max_attempts = max_attempts;
+        settings.circuit_breaker = breaker;
+        settings
+    }
+
+    fn breaker(
+        failure_threshold: u32,
+        cooldown_secs: u64,
+        max_pause_secs: u64,
+    ) -> CircuitBreakerSettings {
+        CircuitBreakerSettings {
+            failure_threshold,
+            cooldown_secs,
+            max_pause_secs,
+        }
+    }
+
+    async fn job(ctx: &AppState) -> JobId {
+        let validated = Validated {
+            valid: vec![parse_entry("1.1.1.1").unwrap()],
+            ..Validated::default()
+        };
+        let job = ctx
+            .jobs
+            .create(
+                "rule".to_string(),
+                ImportMode::Add,
+                &validated,
+                None,
+                Priority::Normal,
+            )
+            .await;
+        job.id
+    }
+
+    /// Apply a change, answering each attempt with the next of `responses`, or success once they
+    /// run out. Returns the outcome and the number of attempts.
+    async fn apply(
+        ctx: &AppState,
+        settings: &Settings,
+        job_id: JobId,
+        cancelled: &mut watch::Receiver<bool>,
+        responses: Vec<Result<(), ApiFailure>>,
+    ) -> (ChangeOutcome, usize) {
+        let attempts = Cell::new(0);
+        let send = || {
+            let response = responses.get(attempts.get()).cloned().unwrap_or(Ok(()));
+            attempts.set(attempts.get() + 1);
+            std::future::ready(response)
+        };
+        let outcome = apply_with_retry(ctx, settings, job_id, &change(), cancelled, send).await;
+        (outcome, attempts.get())
+    }
+
+    fn unavailable(times: usize) -> Vec<Result<(), ApiFailure>> {
+        vec![Err(failure(FailureKind::ServerError, 503)); times]
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transient_failures_are_retried() {
+        let ctx = AppState::for_tests("retry-transient");
+        let settings = settings(4, breaker(1, 30, 60));
+        let mut cancelled = watch::channel(false).1;
+        let mut responses = unavailable(2);
+        responses.push(Err(failure(FailureKind::Rejected, 429)));
+        let (outcome, attempts) = apply(&ctx, &settings, 1, &mut cancelled, responses).await;
+        assert!(matches!(outcome, ChangeOutcome::Applied));
+        assert_eq!(attempts, 4);
+        assert!(ctx.breaker.open_until().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rejections_are_not_retried_and_auth_failures_abort() {
+        let ctx = AppState::for_tests("retry-rejected");
+        let settings = settings(4, breaker(1, 30, 60));
+        let mut cancelled = watch::channel(false).1;
+        let rejected = vec![Err(failure(FailureKind::Rejected, 400))];
+        let (outcome, attempts) = apply(&ctx, &settings, 1, &mut cancelled, rejected).await;
+        assert!(matches!(outcome, ChangeOutcome::Failed(_)));
+        assert_eq!(attempts, 1);
+
+        let unauthorized = vec![Err(failure(FailureKind::AuthFailure, 401))];
+        let (outcome, attempts) = apply(&ctx, &settings, 1, &mut cancelled, unauthorized).await;
+        assert!(matches!(outcome, ChangeOutcome::Abort(_)));
+        assert_eq!(attempts, 1);
+        assert!(ctx.breaker.open_until().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_change_counts_against_the_circuit_once_after_its_retries() {
+        let ctx = AppState::for_tests("retry-count-once");
+        let settings = settings(3, breaker(2, 30, 60));
+        let mut cancelled = watch::channel(false).1;
+        let (outcome, attempts) = apply(&ctx, &settings, 1, &mut cancelled, unavailable(3)).await;
+        assert!(matches!(outcome, ChangeOutcome::Failed(_)));
+        assert_eq!(attempts, 3);
+        assert!(ctx.breaker.open_until().is_none());
+
+        // the second change to run out of retries opens the circuit, and waits it out
+        let start = Instant::now();
+        let (outcome, attempts) = apply(&ctx, &settings, 1, &mut cancelled, unavailable(3)).await;
+        assert!(matches!(outcome, ChangeOutcome::Applied));
+        assert_eq!(attempts, 4);
+        assert!(start.elapsed() >= Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_open_circuit_pauses_the_job_until_it_closes() {
+        let ctx = AppState::for_tests("retry-pause");
+        let job_id = job(&ctx).await;
+        let settings = settings(1, breaker(1, 30, 60));
+        ctx.breaker.record_failure(&settings.circuit_breaker);
+        let mut events = ctx.jobs.subscribe();
+        let mut cancelled = watch::channel(false).1;
+
+        let start = Instant::now();
+        let (outcome, attempts) = apply(&ctx, &settings, job_id, &mut cancelled, vec![]).await;
+        assert!(matches!(outcome, ChangeOutcome::Applied));
+        assert_eq!(attempts, 1);
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+
+        let mut states = Vec::new();
+        while let Ok(JobEvent::Job { job }) = events.try_recv() {
+            states.push(job.state);
+        }
+        assert_eq!(states, [JobState::Paused, JobState::Running]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_job_is_aborted_once_it_would_pause_longer_than_max_pause() {
+        let ctx = AppState::for_tests("retry-max-pause");
+        let settings = settings(1, breaker(1, 30, 60));
+        let mut cancelled = watch::channel(false).1;
+
+        let start = Instant::now();
+        let (outcome, attempts) = apply(&ctx, &settings, 1, &mut cancelled, unavailable(10)).await;
+        assert!(matches!(outcome, ChangeOutcome::Abort(_)));
+        // paused twice for 30s, a third pause would go past 60s
+        assert_eq!(attempts, 3);
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelling_interrupts_the_pause() {
+        let ctx = AppState::for_tests("retry-cancel");
+        let settings = settings(1, breaker(1, 30, 60));
+        ctx.breaker.record_failure(&settings.circuit_breaker);
+        let (cancel, mut cancelled) = watch::channel(false);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            cancel.send_replace(true);
+        });
+
+        let start = Instant::now();
+        let (outcome, attempts) = apply(&ctx, &settings, 1, &mut cancelled, vec![]).await;
+        assert!(matches!(outcome, ChangeOutcome::Cancelled));
+        assert_eq!(attempts, 0);
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelling_interrupts_the_backoff() {
+        let ctx = AppState::for_tests("retry-cancel-backoff");
+        let settings = settings(4, breaker(1, 30, 60));
+        let (cancel, mut cancelled) = watch::channel(false);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            cancel.send_replace(true);
+        });
+
+        let (outcome, attempts) = apply(&ctx, &settings, 1, &mut cancelled, unavailable(4)).await;
+        assert!(matches!(outcome, ChangeOutcome::Cancelled));
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,415 @@
+use std::pin::pin;
+
+use axum::Json;
+use axum::body::{Body, Bytes};
+use axum::extract::{Form, FromRequest, Multipart, Query, Request};
+use axum::http::header;
+use futures_util::StreamExt;
+
+use crate::decompress;
+use crate::errors::Error;
+use crate::parser::{self, ListFormat, ListOptions, ParsedList, RawEntry};
+use crate::plan::ImportMode;
+use crate::queue::Priority;
+
+/// Query string (or urlencoded body) form of an import, kept for backward compatibility.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ImportForm {
+    #[serde(rename = "access_rule_id")]
+    pub access_rule_id: String,
+    #[serde(rename = "blocklist")]
+    // list of IPs, see `parser::parse_list` for the accepted formats
+    pub blocklist: String,
+    #[serde(default)]
+    pub format: ListFormat,
+    #[serde(default)]
+    pub mode: ImportMode,
+    #[serde(default)]
+    pub priority: Priority,
+}
+
+/// JSON body of an import.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ImportJson {
+    pub access_rule_id: String,
+    /// One IP or network per element.
+    #[serde(default)]
+    pub entries: Vec<String>,
+    /// Raw blocklist text, parsed like the textarea in the UI.
+    #[serde(default)]
+    pub blocklist: Option<String>,
+    /// Layout of `blocklist`.
+    #[serde(default)]
+    pub format: ListFormat,
+    #[serde(flatten)]
+    pub options: ListOptions,
+    #[serde(default)]
+    pub mode: ImportMode,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+struct AccessRuleParam {
+    access_rule_id: String,
+}
+
+/// Options that may be given in the query string whatever the body looks like.
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+struct ImportQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Where the entries of an import came from.
+#[derive(Clone, Debug)]
+pub enum ImportSource {
+    /// Blocklist text of the given layout that still has to be split into entries.
+    Text(String, ListFormat, ListOptions),
+    /// Entries that were already split by the client.
+    Entries(Vec<String>),
+}
+
+/// An import request, accepted as a JSON body, a `multipart/form-data` upload or,
+/// for backward compatibility, a query string or urlencoded form.
+#[derive(Clone, Debug)]
+pub struct ImportRequest {
+    pub access_rule_id: String,
+    pub source: ImportSource,
+    pub mode: ImportMode,
+    /// Where the import goes in the queue.
+    pub priority: Priority,
+    /// Only report what the import would change, without calling Zoraxy to change anything.
+    pub dry_run: bool,
+}
+
+impl ImportRequest {
+    /// The candidate entries of the request, with the line (or array index) they came from.
+    pub fn list(&self) -> Result<ParsedList, Error> {
+        Ok(match &self.source {
+            ImportSource::Text(text, format, options) => {
+                parser::parse_list(text, *format, options).map_err(Error::InvalidList)?
+            }
+            ImportSource::Entries(entries) => entries
+                .iter()
+                .enumerate()
+                .map(|(i, entry)| RawEntry {
+                    line: i + 1,
+                    text: entry.trim().to_string(),
+                    reference: None,
+                })
+                .filter(|entry| !entry.text.is_empty())
+                .collect::<Vec<_>>()
+                .into(),
+        })
+    }
+}
+
+impl<S: Send + Sync> FromRequest<S> for ImportRequest {
+    type Rejection = Error;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        // media types are case-insensitive
+        let media_type = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let query = Query::<ImportQuery>::try_from_uri(req.uri())
+            .map_err(|e| Error::InvalidRequest(e.body_text()))?
+            .0;
+
+        let mut request = Self::from_body(req, &media_type, state).await?;
+        request.dry_run |= query.dry_run;
+        Ok(request)
+    }
+}
+
+impl ImportRequest {
+    async fn from_body<S: Send + Sync>(
+        req: Request,
+        media_type: &str,
+        state: &S,
+    ) -> Result<Self, Error> {
+        if media_type.starts_with("application/json") {
+            let Json(body) = Json::<ImportJson>::from_request(req, state)
+                .await
+                .map_err(|e| Error::InvalidRequest(e.body_text()))?;
+            body.options.validate().map_err(Error::InvalidRequest)?;
+            let source = match body.blocklist {
+                Some(text) if body.entries.is_empty() => {
+                    ImportSource::Text(text, body.format, body.options)
+                }
+                Some(_) => {
+                    return Err(Error::InvalidRequest(
+                        "only one of `entries` and `blocklist` may be given".to_string(),
+                    ));
+                }
+                None => ImportSource::Entries(body.entries),
+            };
+            return Ok(ImportRequest {
+                access_rule_id: body.access_rule_id,
+                source,
+                mode: body.mode,
+                priority: body.priority,
+                dry_run: body.dry_run,
+            });
+        }
+
+        if media_type.starts_with("multipart/form-data") {
+            return from_multipart(req, state).await;
+        }
+
+        // legacy form, which the UI used to send in the query string
+        let (parts, body) = req.into_parts();
+        let body = Bytes::from_request(Request::from_parts(parts.clone(), body), state)
+            .await
+            .map_err(|e| Error::InvalidRequest(e.body_text()))?;
+        let form =
+            if media_type.starts_with("application/x-www-form-urlencoded") && !body.is_empty() {
+                let req = Request::from_parts(parts, Body::from(body));
+                Form::<ImportForm>::from_request(req, state)
+                    .await
+                    .map_err(|e| Error::InvalidRequest(e.body_text()))?
+                    .0
+            } else {
+                Query::<ImportForm>::try_from_uri(&parts.uri)
+                    .map_err(|e| Error::InvalidRequest(e.body_text()))?
+                    .0
+            };
+
+        Ok(ImportRequest {
+            access_rule_id: form.access_rule_id,
+            source: ImportSource::Text(form.blocklist, form.format, ListOptions::default()),
+            mode: form.mode,
+            priority: form.priority,
+            dry_run: false,
+        })
+    }
+}
+
+/// Form fields of an upload that are not blocklist files.
+const FIELDS: &[&str] = &[
+    "access_rule_id",
+    "mode",
+    "format",
+    "priority",
+    "columns",
+    "selector",
+    "members",
+    "blocklist",
+];
+
+/// A blocklist file of an upload.
+struct Upload {
+    filename: String,
+    list: decompress::List,
+}
+
+/// Read an upload of one or more blocklist files.
+///
+/// The Access Rule is taken from the `access_rule_id` field, or the query string if the form does
+/// not have one. Every file part, and any `blocklist` text field, contributes entries.
+async fn from_multipart<S: Send + Sync>(req: Request, state: &S) -> Result<ImportRequest, Error> {
+    let query_rule = Query::<AccessRuleParam>::try_from_uri(req.uri())
+        .ok()
+        .map(|q| q.0.access_rule_id);
+    let mut multipart = Multipart::from_request(req, state)
+        .await
+        .map_err(|e| Error::InvalidRequest(e.body_text()))?;
+
+    let mut access_rule_id = query_rule;
+    let mut mode = ImportMode::default();
+    let mut format = ListFormat::default();
+    let mut options = ListOptions::default();
+    let mut priority = Priority::default();
+    let mut texts = Vec::new();
+    let mut files = Vec::new();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| Error::InvalidRequest(e.body_text()))?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+        if !FIELDS.contains(&name.as_str()) {
+            if let Some(filename) = field.file_name() {
+                let filename = filename.to_string();
+                let content_type = field.content_type().map(str::to_string);
+                let chunks = pin!(
+                    field.map(|chunk| chunk.map_err(|e| Error::InvalidRequest(e.body_text())))
+                );
+                let list =
+                    decompress::read_list(chunks, &filename, content_type.as_deref()).await?;
+                files.push(Upload { filename, list });
+            }
+            continue;
+        }
+
+        let data = field
+            .bytes()
+            .await
+            .map_err(|e| Error::InvalidRequest(e.body_text()))?;
+        let text = String::from_utf8_lossy(&data).into_owned();
+        match name.as_str() {
+            "access_rule_id" => access_rule_id = Some(text.trim().to_string()),
+            "mode" => mode = parse_field("mode", &text)?,
+            "format" => format = parse_field("format", &text)?,
+            "priority" => priority = parse_field("priority", &text)?,
+            "blocklist" => texts.push(text),
+            _ if text.trim().is_empty() => {}
+            // a JSON object, like the `columns` of a JSON body
+            "columns" => {
+                options.columns = serde_json::from_str(&text)
+                    .map_err(|e| Error::InvalidRequest(format!("invalid `columns`: {e}")))?;
+            }
+            // a selector string, or a JSON object like the `selector` of a JSON body
+            "selector" => {
+                options.selector = Some(match text.trim_start().starts_with('{') {
+                    true => serde_json::from_str(&text)
+                        .map_err(|e| Error::InvalidRequest(format!("invalid `selector`: {e}")))?,
+                    false => parse_field("selector", &text)?,
+                });
+            }
+            _ => options.members.push(text.trim().to_string()),
+        }
+    }
+
+    let access_rule_id = access_rule_id
+        .filter(|id| !id.is_empty())
+        .ok_or_else(|| Error::InvalidRequest("missing `access_rule_id`".to_string()))?;
+    // files of archives are chosen once every field is read, as `members` may come after them
+    for file in files {
+        texts.push(
+            file.list
+                .into_text(&file.filename, &options.members)
+                .await?,
+        );
+    }
+    if texts.is_empty() {
+        return Err(Error::InvalidRequest(
+            "the upload does not contain a blocklist file".to_string(),
+        ));
+    }
+    options.validate().map_err(Error::InvalidRequest)?;
+
+    Ok(ImportRequest {
+        access_rule_id,
+        // files are joined so each keeps its own lines, line numbers then count across files
+        source: ImportSource::Text(texts.join("\n"), format, options),
+        mode,
+        priority,
+        dry_run: false,
+    })
+}
+
+/// Parse a plain form field into the same type the JSON body would use.
+fn parse_field<T: serde::de::DeserializeOwned>(name: &str, value: &str) -> Result<T, Error> {
+    serde_json::from_value(serde_json::Value::String(value.trim().to_string()))
+        .map_err(|e| Error::InvalidRequest(format!("invalid `{name}`: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn extract(
+        uri: &str,
+        content_type: Option<&str>,
+        body: &str,
+    ) -> Result<ImportRequest, Error> {
+        let mut req = Request::builder().method("POST").uri(uri);
+        if let Some(content_type) = content_type {
+            req = req.header(header::CONTENT_TYPE, content_type);
+        }
+        let req = req.body(Body::from(body.to_string())).unwrap();
+        ImportRequest::from_request(req, &()).await
+    }
+
+    /// The entries of a request, as they were written.
+    fn entries(request: &ImportRequest) -> Vec<String> {
+        let list = request.list().unwrap();
+        list.entries.into_iter().map(|entry| entry.text).collect()
+    }
+
+    #[tokio::test]
+    async fn json_bodies_give_entries_or_a_blocklist() {
+        let body = r#"{"access_rule_id": "rule", "entries": ["1.1.1.1", " ", "10.0.0.0/8"],
+            "mode": "sync", "priority": "high"}"#;
+        let request = extract("/api/import", Some("application/json"), body)
+            .await
+            .unwrap();
+        assert_eq!(request.access_rule_id, "rule");
+        assert_eq!(request.mode, ImportMode::Sync);
+        assert_eq!(request.priority, Priority::High);
+        assert!(!request.dry_run);
+        assert_eq!(entries(&request), ["1.1.1.1", "10.0.0.0/8"]);
+
+        let body = r#"{"access_rule_id": "rule", "blocklist": "1.1.1.1 # bad\n2.2.2.2"}"#;
+        let request = extract("/api/import?dry_run=true", Some("Application/JSON"), body)
+            .await
+            .unwrap();
+        assert!(request.dry_run);
+        assert_eq!(entries(&request), ["1.1.1.1", "2.2.2.2"]);
+
+        let body = r#"{"access_rule_id": "rule", "entries": ["1.1.1.1"], "blocklist": "2.2.2.2"}"#;
+        let error = extract("/api/import", Some("application/json"), body).await;
+        assert!(matches!(error, Err(Error::InvalidRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn uploads_join_their_files_and_read_their_fields() {
+        let body = "--b\r\n\
+            Content-Disposition: form-data; name=\"mode\"\r\n\r\n\
+            sync\r\n\
+            --b\r\n\
+            Content-Disposition: form-data; name=\"first\"; filename=\"a.txt\"\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            1.1.1.1\n# comment\r\n\
+            --b\r\n\
+            Content-Disposition: form-data; name=\"second\"; filename=\"b.txt\"\r\n\r\n\
+            2.2.2.0/24\r\n\
+            --b--\r\n";
+        let request = extract(
+            "/api/import?access_rule_id=rule",
+            Some("multipart/form-data; boundary=b"),
+            body,
+        )
+        .await
+        .unwrap();
+        assert_eq!(request.access_rule_id, "rule");
+        assert_eq!(request.mode, ImportMode::Sync);
+        assert_eq!(entries(&request), ["1.1.1.1", "2.2.2.0/24"]);
+
+        let body = "--b\r\n\
+            Content-Disposition: form-data; name=\"access_rule_id\"\r\n\r\n\
+            rule\r\n\
+            --b--\r\n";
+        let error = extract("/api/import", Some("multipart/form-data; boundary=b"), body).await;
+        assert!(matches!(error, Err(Error::InvalidRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn the_legacy_query_string_and_form_are_accepted() {
+        let uri = "/api/import?access_rule_id=rule&blocklist=1.1.1.1%0A2.2.2.2&mode=sync";
+        let request = extract(uri, None, "").await.unwrap();
+        assert_eq!(request.access_rule_id, "rule");
+        assert_eq!(request.mode, ImportMode::Sync);
+        assert_eq!(entries(&request), ["1.1.1.1", "2.2.2.2"]);
+
+        let body = "access_rule_id=rule&blocklist=3.3.3.3";
+        let request = extract(
+            "/api/import",
+            Some("application/x-www-form-urlencoded"),
+            body,
+        )
+        .await
+        .unwrap();
+        assert_eq!(entries(&request), ["3.3.3.3"]);
+
+        let error = extract("/api/import?blocklist=1.1.1.1", None, "").await;
+        assert!(matches!(error, Err(Error::InvalidRequest(_))));
+    }
+}
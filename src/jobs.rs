@@ -0,0 +1,935 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use axum::{Json, debug_handler};
+use tokio::sync::{RwLock, watch};
+
+use crate::AppState;
+use crate::errors::Error;
+use crate::events::{Events, JobEvent};
+use crate::feeds::FeedId;
+use crate::journal::{Journal, Outcome, Record};
+use crate::parser::ListMetadata;
+use crate::plan::{Action, Change, ImportMode};
+use crate::queue::Priority;
+use crate::safelist::Conflict;
+use crate::store::Changes;
+use crate::validate::{Validated, canonical};
+use crate::zoraxy_client::{ApiFailure, FailureKind};
+
+pub type JobId = u64;
+
+/// Number of jobs kept in the history, the oldest finished jobs are forgotten beyond this.
+const MAX_JOBS: usize = 500;
+
+/// Seconds since the Unix epoch, used for all job timestamps.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    /// Waiting for Zoraxy to become reachable again, see [`crate::retry::CircuitBreaker`].
+    Paused,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// A single import of a list of IPs into an Access Rule.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Job {
+    pub id: JobId,
+    pub access_rule_id: String,
+    pub mode: ImportMode,
+    /// The feed subscription that started this job, if any.
+    pub feed_id: Option<FeedId>,
+    /// The job this job undoes, if it is a rollback.
+    pub rollback_of: Option<JobId>,
+    /// The latest rollback started for this job.
+    pub rolled_back_by: Option<JobId>,
+    /// What the imported list says about itself, if it says anything.
+    pub list: Option<Box<ListMetadata>>,
+    #[serde(default)]
+    pub priority: Priority,
+    pub state: JobState,
+    /// Number of valid entries the job was asked to import.
+    pub submitted: usize,
+    /// Entries of the submitted list that were not valid IPs or networks, and were not sent.
+    pub invalid: usize,
+    /// Entries that overlap the safelist, and were not sent.
+    #[serde(default)]
+    pub safelisted: usize,
+    /// Rows of the submitted list left out by the filters of the import.
+    #[serde(default)]
+    pub filtered: usize,
+    /// Entries that were repeated in the submitted list.
+    pub duplicates: usize,
+    /// Entries that were merged into a larger network, or covered by another entry of the list.
+    #[serde(default)]
+    pub aggregated: usize,
+    /// Entries the Access Rule already had, which are not sent again. For a rollback, changes
+    /// the Access Rule no longer reflects, which are left alone.
+    pub already_present: usize,
+    /// Entries of the Access Rule that are not in the list, and are removed in sync mode.
+    pub to_remove: usize,
+    /// Number of changes (additions and removals) that need to be sent to Zoraxy, known once
+    /// the job has compared the list against the Access Rule.
+    pub total: usize,
+    /// Number of IPs that have been sent to Zoraxy so far, successfully or not.
+    pub processed: usize,
+    pub succeeded: usize,
+    /// Successful removals, also counted in `succeeded`.
+    pub removed: usize,
+    pub failed: usize,
+    pub created_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    /// When a paused job will next try to reach Zoraxy.
+    pub resume_at: Option<u64>,
+    /// Set when the job as a whole failed, rather than individual IPs.
+    pub error: Option<String>,
+}
+
+impl Job {
+    pub fn new(
+        id: JobId,
+        access_rule_id: String,
+        mode: ImportMode,
+        submitted: usize,
+        invalid: usize,
+        safelisted: usize,
+        feed_id: Option<FeedId>,
+    ) -> Self {
+        Self {
+            id,
+            access_rule_id,
+            mode,
+            feed_id,
+            rollback_of: None,
+            rolled_back_by: None,
+            list: None,
+            priority: Priority::default(),
+            state: JobState::Queued,
+            submitted,
+            invalid,
+            safelisted,
+            filtered: 0,
+            duplicates: 0,
+            aggregated: 0,
+            already_present: 0,
+            to_remove: 0,
+            total: 0,
+            processed: 0,
+            succeeded: 0,
+            removed: 0,
+            failed: 0,
+            created_at: unix_now(),
+            started_at: None,
+            finished_at: None,
+            resume_at: None,
+            error: None,
+        }
+    }
+}
+
+/// An entry of a job that did not make it into Zoraxy, and why.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct ImportFailure {
+    pub action: Action,
+    pub entry: String,
+    pub kind: FailureKind,
+    /// HTTP status returned by Zoraxy, if it responded at all.
+    pub status: Option<u16>,
+    pub message: String,
+}
+
+impl ImportFailure {
+    pub fn new(change: &Change, failure: ApiFailure) -> Self {
+        Self {
+            action: change.action,
+            entry: change.entry.clone(),
+            kind: failure.kind,
+            status: failure.status,
+            message: failure.message,
+        }
+    }
+}
+
+/// Progress of an unfinished job, read back from its [`Journal`] to pick the job up again after a
+/// restart.
+#[derive(Clone, Debug, Default)]
+pub struct Checkpoint {
+    /// The entries to import, kept until the job has planned its changes.
+    pub entries: Vec<String>,
+    /// The changes the job has to make, once it has compared the list against the Access Rule.
+    pub changes: Option<Vec<Change>>,
+    /// Indices into `changes` of the changes that have been sent to Zoraxy, successfully or not.
+    pub done: BTreeSet<usize>,
+}
+
+impl Checkpoint {
+    /// The planned changes that have not been sent yet, with their index in the plan.
+    pub fn remaining(&self) -> Vec<(usize, Change)> {
+        self.changes
+            .iter()
+            .flatten()
+            .enumerate()
+            .filter(|(i, _)| !self.done.contains(i))
+            .map(|(i, change)| (i, change.clone()))
+            .collect()
+    }
+}
+
+/// The jobs as saved in the state file. What each job was asked to do and what it did is kept in
+/// its [`Journal`] instead.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Registry {
+    next_id: JobId,
+    pub jobs: BTreeMap<JobId, Job>,
+}
+
+impl Registry {
+    /// Forget the oldest finished jobs once there are more than [`MAX_JOBS`], returning their IDs.
+    fn prune(&mut self) -> Vec<JobId> {
+        let excess = self.jobs.len().saturating_sub(MAX_JOBS);
+        let old: Vec<JobId> = self
+            .jobs
+            .values()
+            .filter(|job| job.finished_at.is_some())
+            .map(|job| job.id)
+            .take(excess)
+            .collect();
+        for id in &old {
+            self.jobs.remove(id);
+        }
+        old
+    }
+}
+
+/// Shared record of the import jobs started by the plugin, saved by the [`crate::store`].
+#[derive(Clone, Debug)]
+pub struct JobRegistry {
+    inner: Arc<RwLock<Registry>>,
+    journal: Journal,
+    changes: Changes,
+    /// Cancellation signals of the jobs that have not finished yet.
+    cancels: Arc<Mutex<HashMap<JobId, watch::Sender<bool>>>>,
+    events: Events,
+}
+
+impl JobRegistry {
+    pub fn new(registry: Registry, journal: Journal, changes: Changes) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(registry)),
+            journal,
+            changes,
+            cancels: Arc::default(),
+            events: Events::default(),
+        }
+    }
+
+    /// Receive an event for every change to a job.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<JobEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn publish(&self, event: JobEvent) {
+        self.events.publish(event);
+    }
+
+    pub async fn snapshot(&self) -> Registry {
+        self.inner.read().await.clone()
+    }
+
+    /// Register a new job importing the valid entries of `validated` in the `Queued` state and
+    /// return a snapshot of it.
+    pub async fn create(
+        &self,
+        access_rule_id: String,
+        mode: ImportMode,
+        validated: &Validated,
+        feed_id: Option<FeedId>,
+        priority: Priority,
+    ) -> Job {
+        let entries = &validated.valid;
+        let mut registry = self.inner.write().await;
+        registry.next_id += 1;
+        let mut job = Job::new(
+            registry.next_id,
+            access_rule_id,
+            mode,
+            entries.len(),
+            validated.invalid.len(),
+            validated.conflicts.len(),
+            feed_id,
+        );
+        job.priority = priority;
+        job.filtered = validated.filtered;
+        job.list = validated.metadata.clone();
+        registry.jobs.insert(job.id, job.clone());
+        let pruned = registry.prune();
+        drop(registry);
+
+        let references = validated
+            .references
+            .iter()
+            .map(|(net, reference)| (canonical(net), reference.clone()))
+            .collect();
+        self.journal
+            .create(
+                job.id,
+                entries.iter().map(canonical).collect(),
+                references,
+                validated.conflicts.clone(),
+            )
+            .await;
+        self.forget(pruned).await;
+        self.changes.notify();
+        self.publish(JobEvent::Job { job: job.clone() });
+        job
+    }
+
+    /// Delete the files of jobs that were pruned from the registry.
+    async fn forget(&self, pruned: Vec<JobId>) {
+        for id in pruned {
+            self.journal.remove(id).await;
+        }
+    }
+
+    pub async fn get(&self, id: JobId) -> Option<Job> {
+        self.inner.read().await.jobs.get(&id).cloned()
+    }
+
+    /// All known jobs, most recent first.
+    pub async fn list(&self) -> Vec<Job> {
+        self.inner
+            .read()
+            .await
+            .jobs
+            .values()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    /// Apply `f` to the job with the given ID, if it exists.
+    pub async fn update(&self, id: JobId, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.inner.write().await.jobs.get_mut(&id) {
+            f(job);
+            self.changes.notify();
+            self.publish(JobEvent::Job { job: job.clone() });
+        }
+    }
+
+    /// Register a job in the `Queued` state that undoes the `applied` changes of `original`, and
+    /// return a snapshot of it. The changes to make are worked out when the job runs.
+    pub async fn create_rollback(&self, original: &Job, applied: usize) -> Job {
+        let mut registry = self.inner.write().await;
+        registry.next_id += 1;
+        let mut job = Job::new(
+            registry.next_id,
+            original.access_rule_id.clone(),
+            original.mode,
+            applied,
+            0,
+            0,
+            None,
+        );
+        job.rollback_of = Some(original.id);
+        job.priority = Priority::High;
+        registry.jobs.insert(job.id, job.clone());
+        if let Some(original) = registry.jobs.get_mut(&original.id) {
+            original.rolled_back_by = Some(job.id);
+            self.publish(JobEvent::Job {
+                job: original.clone(),
+            });
+        }
+        let pruned = registry.prune();
+        drop(registry);
+
+        self.journal
+            .create(job.id, Vec::new(), BTreeMap::new(), Vec::new())
+            .await;
+        self.forget(pruned).await;
+        self.changes.notify();
+        self.publish(JobEvent::Job { job: job.clone() });
+        job
+    }
+
+    async fn contains(&self, id: JobId) -> bool {
+        self.inner.read().await.jobs.contains_key(&id)
+    }
+
+    /// The changes a job made, in the order they were made, `None` if the job does not exist.
+    pub async fn applied(&self, id: JobId) -> Option<Vec<Change>> {
+        if !self.contains(id).await {
+            return None;
+        }
+        let records = self.journal.records(id).await;
+        Some(
+            records
+                .into_iter()
+                .filter_map(|record| match record.outcome {
+                    Outcome::Applied(change) => Some(change),
+                    Outcome::Failed(_) => None,
+                })
+                .collect(),
+        )
+    }
+
+    /// The list's own references for the entries of a job, by entry.
+    pub async fn references(&self, id: JobId) -> BTreeMap<String, String> {
+        self.journal.references(id).await
+    }
+
+    /// The entries of a job that were held back by the safelist, `None` if the job does not exist.
+    pub async fn conflicts(&self, id: JobId) -> Option<Vec<Conflict>> {
+        match self.contains(id).await {
+            true => Some(self.journal.conflicts(id).await),
+            false => None,
+        }
+    }
+
+    /// Read back what is left to do of a job interrupted by a restart, `None` if it was saved
+    /// without a checkpoint or its checkpoint can't be read.
+    ///
+    /// The job's counters are brought up to date with the outcomes saved before the plugin
+    /// stopped, which the state file may not have caught up with.
+    pub async fn restore(&self, id: JobId) -> Option<Checkpoint> {
+        if !self.journal.exists(id).await {
+            return None;
+        }
+        let Some(changes) = self.journal.plan(id).await else {
+            return Some(Checkpoint {
+                entries: self.journal.entries(id).await?,
+                ..Checkpoint::default()
+            });
+        };
+
+        let records = self.journal.records(id).await;
+        self.update(id, |job| {
+            job.processed = records.len();
+            job.succeeded = 0;
+            job.removed = 0;
+            job.failed = 0;
+            for record in &records {
+                match &record.outcome {
+                    Outcome::Applied(change) => {
+                        job.succeeded += 1;
+                        if change.action == Action::Remove {
+                            job.removed += 1;
+                        }
+                    }
+                    Outcome::Failed(_) => job.failed += 1,
+                }
+            }
+        })
+        .await;
+        Some(Checkpoint {
+            entries: Vec::new(),
+            changes: Some(changes),
+            done: records.iter().map(|record| record.index).collect(),
+        })
+    }
+
+    /// Save the changes a job has planned, which replace its list of entries in the checkpoint.
+    pub async fn checkpoint_plan(&self, id: JobId, changes: &[Change]) {
+        self.journal.write_plan(id, changes.to_vec()).await;
+    }
+
+    /// Save the entries a job held back because they overlap the safelist, which the job only
+    /// knows once it runs.
+    pub async fn record_conflicts(&self, id: JobId, conflicts: &[Conflict]) {
+        self.update(id, |job| job.safelisted = conflicts.len())
+            .await;
+        self.journal.write_conflicts(id, conflicts.to_vec()).await;
+    }
+
+    /// The failure report of a job, `None` if the job does not exist.
+    pub async fn failures(&self, id: JobId) -> Option<Vec<ImportFailure>> {
+        if !self.contains(id).await {
+            return None;
+        }
+        let records = self.journal.records(id).await;
+        Some(
+            records
+                .into_iter()
+                .filter_map(|record| match record.outcome {
+                    Outcome::Applied(_) => None,
+                    Outcome::Failed(failure) => Some(failure),
+                })
+                .collect(),
+        )
+    }
+
+    /// Count the outcome of the change at `index` of the job's plan, and append it to the job's
+    /// results. Returns a snapshot of the updated job.
+    ///
+    /// The state file is not saved for it, the counters are worked out again from the results
+    /// if the job is interrupted, see [`JobRegistry::restore`].
+    pub async fn record_result(
+        &self,
+        id: JobId,
+        index: usize,
+        change: &Change,
+        result: Result<(), ApiFailure>,
+    ) -> Option<Job> {
+        let job = {
+            let mut registry = self.inner.write().await;
+            let job = registry.jobs.get_mut(&id)?;
+            job.processed += 1;
+            match &result {
+                Ok(()) => {
+                    job.succeeded += 1;
+                    if change.action == Action::Remove {
+                        job.removed += 1;
+                    }
+                }
+                Err(_) => job.failed += 1,
+            }
+            job.clone()
+        };
+        let outcome = match result {
+            Ok(()) => Outcome::Applied(change.clone()),
+            Err(failure) => Outcome::Failed(ImportFailure::new(change, failure)),
+        };
+        self.journal.append(id, &Record { index, outcome }).await;
+        Some(job)
+    }
+
+    pub async fn mark_running(&self, id: JobId) {
+        self.update(id, |job| {
+            job.state = JobState::Running;
+            job.started_at = Some(unix_now());
+        })
+        .await;
+    }
+
+    pub async fn mark_paused(&self, id: JobId, resume_at: u64) {
+        self.update(id, |job| {
+            job.state = JobState::Paused;
+            job.resume_at = Some(resume_at);
+        })
+        .await;
+    }
+
+    pub async fn mark_resumed(&self, id: JobId) {
+        self.update(id, |job| {
+            job.state = JobState::Running;
+            job.resume_at = None;
+        })
+        .await;
+    }
+
+    /// Signal that becomes `true` once the job is cancelled.
+    pub fn cancellation(&self, id: JobId) -> watch::Receiver<bool> {
+        self.cancels
+            .lock()
+            .expect("cancel lock poisoned")
+            .entry(id)
+            .or_insert_with(|| watch::channel(false).0)
+            .subscribe()
+    }
+
+    /// Ask an unfinished job to stop. It stops once the changes already being sent are done.
+    pub async fn cancel(&self, id: JobId) -> Result<Job, Error> {
+        // hold the registry so the job can't finish in between
+        let registry = self.inner.read().await;
+        let job = registry.jobs.get(&id).ok_or(Error::JobNotFound(id))?;
+        if job.finished_at.is_some() {
+            return Err(Error::JobFinished(id));
+        }
+        self.cancels
+            .lock()
+            .expect("cancel lock poisoned")
+            .entry(id)
+            .or_insert_with(|| watch::channel(false).0)
+            .send_replace(true);
+        Ok(job.clone())
+    }
+
+    pub async fn mark_finished(&self, id: JobId, state: JobState, error: Option<String>) {
+        self.cancels
+            .lock()
+            .expect("cancel lock poisoned")
+            .remove(&id);
+        self.journal.finish(id).await;
+        self.update(id, |job| {
+            job.state = state;
+            job.error = error;
+            job.finished_at = Some(unix_now());
+            job.resume_at = None;
+        })
+        .await;
+    }
+}
+
+#[debug_handler]
+pub async fn handle_list_jobs(State(state): State<AppState>) -> Json<Vec<Job>> {
+    Json(state.jobs.list().await)
+}
+
+#[debug_handler]
+pub async fn handle_get_job(
+    State(state): State<AppState>,
+    Path(id): Path<JobId>,
+) -> Result<Json<Job>, Error> {
+    state
+        .jobs
+        .get(id)
+        .await
+        .map(Json)
+        .ok_or(Error::JobNotFound(id))
+}
+
+#[debug_handler]
+pub async fn handle_cancel_job(
+    State(state): State<AppState>,
+    Path(id): Path<JobId>,
+) -> Result<Response, Error> {
+    let mut job = state.jobs.cancel(id).await?;
+    tracing::info!(job_id = id, "Cancelling import");
+    // a job that has not started yet has nothing to wind down
+    if state.queue.remove(id) {
+        state
+            .jobs
+            .mark_finished(
+                id,
+                JobState::Cancelled,
+                Some("Cancelled before it started".to_string()),
+            )
+            .await;
+        job = state.jobs.get(id).await.ok_or(Error::JobNotFound(id))?;
+    }
+    Ok((StatusCode::ACCEPTED, Json(job)).into_response())
+}
+
+#[debug_handler]
+pub async fn handle_get_job_conflicts(
+    State(state): State<AppState>,
+    Path(id): Path<JobId>,
+) -> Result<Json<Vec<Conflict>>, Error> {
+    state
+        .jobs
+        .conflicts(id)
+        .await
+        .map(Json)
+        .ok_or(Error::JobNotFound(id))
+}
+
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ReportQuery {
+    #[serde(default)]
+    pub format: ReportFormat,
+}
+
+#[debug_handler]
+pub async fn handle_get_job_failures(
+    State(state): State<AppState>,
+    Path(id): Path<JobId>,
+    Query(query): Query<ReportQuery>,
+) -> Result<Response, Error> {
+    let failures = state
+        .jobs
+        .failures(id)
+        .await
+        .ok_or(Error::JobNotFound(id))?;
+
+    Ok(match query.format {
+        ReportFormat::Json => Json(failures).into_response(),
+        ReportFormat::Csv => (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"job-{id}-failures.csv\""),
+                ),
+            ],
+            failures_to_csv(&failures),
+        )
+            .into_response(),
+    })
+}
+
+fn failures_to_csv(failures: &[ImportFailure]) -> String {
+    let mut csv = String::from("action,entry,kind,status,message\r\n");
+    for failure in failures {
+        let status = failure.status.map(|s| s.to_string()).unwrap_or_default();
+        csv.push_str(&format!(
+            "{},{},{},{},{}\r\n",
+            failure.action.as_str(),
+            csv_field(&failure.entry),
+            failure.kind.as_str(),
+            status,
+            csv_field(&failure.message)
+        ));
+    }
+    csv
+}
+
+/// Quote a CSV field if it contains characters that would otherwise break the row.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate::parse_entry;
+
+    /// A registry of no jobs keeping their files in a fresh temporary directory.
+    fn registry(name: &str) -> JobRegistry {
+        let dir = std::env::temp_dir().join(format!("jobs-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        JobRegistry::new(Registry::default(), Journal::new(dir), Changes::default())
+    }
+
+    async fn create(jobs: &JobRegistry, entries: &[&str]) -> Job {
+        let validated = Validated {
+            valid: entries.iter().map(|e| parse_entry(e).unwrap()).collect(),
+            ..Validated::default()
+        };
+        jobs.create(
+            "rule".to_string(),
+            ImportMode::Add,
+            &validated,
+            None,
+            Priority::Normal,
+        )
+        .await
+    }
+
+    fn change(action: Action, entry: &str) -> Change {
+        Change {
+            action,
+            entry: entry.to_string(),
+        }
+    }
+
+    fn rejected() -> ApiFailure {
+        ApiFailure {
+            kind: FailureKind::Rejected,
+            status: Some(400),
+            message: "rejected".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn jobs_are_listed_most_recent_first() {
+        let jobs = registry("list");
+        let first = create(&jobs, &["1.1.1.1", "2.2.2.2", "3.3.3.3"]).await;
+        let second = create(&jobs, &["4.4.4.4"]).await;
+        assert_eq!((first.id, second.id), (1, 2));
+        assert_eq!(first.state, JobState::Queued);
+
+        let ids: Vec<JobId> = jobs.list().await.iter().map(|job| job.id).collect();
+        assert_eq!(ids, [2, 1]);
+        assert!(jobs.get(3).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_job_records_when_it_runs_and_how_it_ends() {
+        let jobs = registry("states");
+        let job = create(&jobs, &["1.1.1.1", "2.2.2.2"]).await;
+
+        jobs.mark_running(job.id).await;
+        let running = jobs.get(job.id).await.unwrap();
+        assert_eq!(running.state, JobState::Running);
+        assert!(running.started_at.is_some() && running.finished_at.is_none());
+
+        let error = Some("Zoraxy is unreachable".to_string());
+        jobs.mark_finished(job.id, JobState::Failed, error.clone())
+            .await;
+        let failed = jobs.get(job.id).await.unwrap();
+        assert_eq!(failed.state, JobState::Failed);
+        assert_eq!(failed.error, error);
+        assert!(failed.finished_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn restoring_a_job_counts_its_saved_results() {
+        let jobs = registry("restore");
+        let job = create(&jobs, &["1.1.1.1", "2.2.2.2", "3.3.3.3"]).await;
+        let changes = vec![
+            change(Action::Add, "1.1.1.1/32"),
+            change(Action::Remove, "4.4.4.4/32"),
+            change(Action::Add, "2.2.2.2/32"),
+        ];
+        jobs.checkpoint_plan(job.id, &changes).await;
+        jobs.record_result(job.id, 1, &changes[1], Ok(()))
+            .await
+            .unwrap();
+        jobs.record_result(job.id, 2, &changes[2], Err(rejected()))
+            .await
+            .unwrap();
+
+        // the state file was last saved before any change was made
+        let mut saved = jobs.snapshot().await;
+        let stale = saved.jobs.get_mut(&job.id).unwrap();
+        (
+            stale.processed,
+            stale.succeeded,
+            stale.removed,
+            stale.failed,
+        ) = (0, 0, 0, 0);
+        let restarted = JobRegistry::new(saved, jobs.journal.clone(), Changes::default());
+
+        let checkpoint = restarted.restore(job.id).await.unwrap();
+        assert_eq!(checkpoint.remaining(), [(0, changes[0].clone())]);
+        let job = restarted.get(job.id).await.unwrap();
+        assert_eq!(
+            (job.processed, job.succeeded, job.removed, job.failed),
+            (2, 1, 1, 1)
+        );
+        assert_eq!(restarted.failures(job.id).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn restoring_a_job_that_has_not_planned_gives_back_its_entries() {
+        let jobs = registry("restore-entries");
+        let job = create(&jobs, &["1.1.1.1", "10.0.0.0/8"]).await;
+        let checkpoint = jobs.restore(job.id).await.unwrap();
+        assert_eq!(checkpoint.entries, ["1.1.1.1", "10.0.0.0/8"]);
+        assert!(checkpoint.changes.is_none());
+
+        // a job saved without any files can't be picked up again
+        jobs.journal.remove(job.id).await;
+        assert!(jobs.restore(job.id).await.is_none());
+    }
+
+    #[test]
+    fn pruning_forgets_the_oldest_finished_jobs() {
+        let mut registry = Registry::default();
+        for id in 1..=MAX_JOBS as JobId + 3 {
+            let mut job = Job::new(id, "rule".to_string(), ImportMode::Add, 0, 0, 0, None);
+            // the oldest job is still running
+            if id != 1 {
+                job.finished_at = Some(id);
+            }
+            registry.jobs.insert(id, job);
+        }
+        assert_eq!(registry.prune(), [2, 3, 4]);
+        assert_eq!(registry.jobs.len(), MAX_JOBS);
+        assert!(registry.jobs.contains_key(&1));
+        assert!(registry.prune().is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_unfinished_jobs_can_be_cancelled() {
+        let jobs = registry("cancel");
+        let job = create(&jobs, &["1.1.1.1"]).await;
+        let cancelled = jobs.cancellation(job.id);
+        assert!(!*cancelled.borrow());
+
+        assert_eq!(jobs.cancel(job.id).await.unwrap().id, job.id);
+        assert!(*cancelled.borrow());
+        // a worker that starts after the job was cancelled sees it too
+        assert!(*jobs.cancellation(job.id).borrow());
+
+        jobs.mark_finished(job.id, JobState::Cancelled, None).await;
+        assert!(matches!(
+            jobs.cancel(job.id).await,
+            Err(Error::JobFinished(_))
+        ));
+        assert!(matches!(jobs.cancel(42).await, Err(Error::JobNotFound(42))));
+    }
+
+    #[tokio::test]
+    async fn failures_are_kept_per_job() {
+        let jobs = registry("failures");
+        let job = create(&jobs, &["1.1.1.1", "2.2.2.2"]).await;
+        let removal = change(Action::Remove, "1.1.1.1/32");
+        jobs.record_result(job.id, 0, &removal, Ok(())).await;
+        let addition = change(Action::Add, "2.2.2.2/32");
+        jobs.record_result(job.id, 1, &addition, Err(rejected()))
+            .await;
+
+        let job = jobs.get(job.id).await.unwrap();
+        assert_eq!(
+            (job.processed, job.succeeded, job.removed, job.failed),
+            (2, 1, 1, 1)
+        );
+        let failures = jobs.failures(job.id).await.unwrap();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].entry, "2.2.2.2/32");
+        assert_eq!(failures[0].status, Some(400));
+        assert!(jobs.failures(42).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn conflicts_found_while_running_are_reported_with_the_job() {
+        let jobs = registry("conflicts");
+        let original = create(&jobs, &["1.1.1.1"]).await;
+        let rollback = jobs.create_rollback(&original, 1).await;
+        assert!(jobs.conflicts(rollback.id).await.unwrap().is_empty());
+
+        let conflict = Conflict {
+            entry: "1.1.1.1".to_string(),
+            safelisted: "1.1.1.0/24".to_string(),
+            reason: "safelist".to_string(),
+        };
+        jobs.record_conflicts(rollback.id, &[conflict]).await;
+        assert_eq!(jobs.get(rollback.id).await.unwrap().safelisted, 1);
+        let conflicts = jobs.conflicts(rollback.id).await.unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].safelisted, "1.1.1.0/24");
+    }
+
+    fn failure(entry: &str, status: Option<u16>, message: &str) -> ImportFailure {
+        ImportFailure {
+            action: Action::Add,
+            entry: entry.to_string(),
+            kind: FailureKind::Rejected,
+            status,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn csv_fields_that_would_break_the_row_are_quoted() {
+        assert_eq!(csv_field("1.1.1.1"), "1.1.1.1");
+        assert_eq!(csv_field("a, b"), "\"a, b\"");
+        assert_eq!(csv_field("say \"no\""), "\"say \"\"no\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("two\r\nlines"), "\"two\r\nlines\"");
+    }
+
+    #[test]
+    fn failures_are_reported_one_per_row() {
+        let csv = failures_to_csv(&[
+            failure("1.1.1.1", Some(400), "invalid, \"really\""),
+            failure("2.2.2.2", None, "timed out\nafter 30s"),
+        ]);
+        assert_eq!(
+            csv,
+            "action,entry,kind,status,message\r\n\
+             add,1.1.1.1,rejected,400,\"invalid, \"\"really\"\"\"\r\n\
+             add,2.2.2.2,rejected,,\"timed out\nafter 30s\"\r\n"
+        );
+    }
+}
@@ -0,0 +1,499 @@
+//! Files of each job, kept in a directory next to the state file so the state file stays small
+//! however long the imported lists are.
+//!
+//! What a job was asked to do is written once: its entries when it is created, replaced by its
+//! planned changes once it has compared the list against the Access Rule. What it did is appended
+//! to its results one change at a time, so saving progress never rewrites what came before.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+use crate::jobs::{ImportFailure, JobId};
+use crate::plan::Change;
+use crate::safelist::Conflict;
+
+/// The entries of a job that has not planned its changes yet.
+const ENTRIES: &str = "entries.json";
+/// The changes of a job that has planned them and not finished yet.
+const PLAN: &str = "plan.json";
+/// The outcome of each change the job sent, one [`Record`] per line.
+const RESULTS: &str = "results.jsonl";
+/// The list's own references for the job's entries.
+const REFERENCES: &str = "references.json";
+/// The entries of the job held back by the safelist.
+const CONFLICTS: &str = "conflicts.json";
+
+/// What became of a change sent to Zoraxy.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Applied(Change),
+    Failed(ImportFailure),
+}
+
+/// A line of a job's results.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Record {
+    /// Index of the change in the job's plan.
+    pub index: usize,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+/// The results file of a job being run, opened when the first outcome is appended to it.
+type OpenResults = Arc<Mutex<Option<tokio::fs::File>>>;
+
+/// The directory holding the files of every job, one subdirectory per job.
+#[derive(Clone, Debug)]
+pub struct Journal {
+    dir: Arc<PathBuf>,
+    /// Results of the jobs being run, kept open while they are appended to. Each has its own
+    /// lock, so the workers of one job never wait for another job's writes.
+    open: Arc<std::sync::Mutex<HashMap<JobId, OpenResults>>>,
+}
+
+impl Journal {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir: Arc::new(dir),
+            open: Arc::default(),
+        }
+    }
+
+    fn job_dir(&self, id: JobId) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    /// The results file of a job being run, which is only opened on first use.
+    fn results_of(&self, id: JobId) -> OpenResults {
+        let mut open = self.open.lock().expect("journal lock poisoned");
+        Arc::clone(open.entry(id).or_default())
+    }
+
+    /// Stop appending to the results of a job, returning the file if it was open.
+    fn close_results(&self, id: JobId) -> Option<OpenResults> {
+        self.open.lock().expect("journal lock poisoned").remove(&id)
+    }
+
+    /// Start the files of a new job. Files left by an earlier job with the same id, which the
+    /// state file may have lost track of, are deleted first.
+    pub async fn create(
+        &self,
+        id: JobId,
+        entries: Vec<String>,
+        references: BTreeMap<String, String>,
+        conflicts: Vec<Conflict>,
+    ) {
+        self.remove(id).await;
+        let dir = self.job_dir(id);
+        if let Err(e) = tokio::fs::create_dir_all(&dir).await {
+            tracing::error!(job_id = id, "Could not create {}: {e}", dir.display());
+            return;
+        }
+        write(dir.join(ENTRIES), entries).await;
+        if !references.is_empty() {
+            write(dir.join(REFERENCES), references).await;
+        }
+        if !conflicts.is_empty() {
+            write(dir.join(CONFLICTS), conflicts).await;
+        }
+    }
+
+    /// Save the entries a job held back once it was running, in place of any it held back before.
+    pub async fn write_conflicts(&self, id: JobId, conflicts: Vec<Conflict>) {
+        write(self.job_dir(id).join(CONFLICTS), conflicts).await;
+    }
+
+    /// Whether the job has files, which every job has until it is forgotten.
+    pub async fn exists(&self, id: JobId) -> bool {
+        tokio::fs::try_exists(self.job_dir(id))
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Save the changes a job has planned, which replace its entries and the results of any
+    /// earlier plan.
+    pub async fn write_plan(&self, id: JobId, changes: Vec<Change>) {
+        let dir = self.job_dir(id);
+        write(dir.join(PLAN), changes).await;
+        self.close_results(id);
+        remove_file(&dir.join(RESULTS)).await;
+        remove_file(&dir.join(ENTRIES)).await;
+    }
+
+    /// Append the outcome of a change to the job's results.
+    pub async fn append(&self, id: JobId, record: &Record) {
+        let mut line = serde_json::to_vec(record).expect("a job record always serializes");
+        line.push(b'\n');
+
+        let results = self.results_of(id);
+        let mut results = results.lock().await;
+        let file = match &mut *results {
+            Some(file) => file,
+            None => {
+                let path = self.job_dir(id).join(RESULTS);
+                match open_results(&path).await {
+                    Ok(file) => results.insert(file),
+                    Err(e) => {
+                        tracing::error!(job_id = id, "Could not open {}: {e}", path.display());
+                        return;
+                    }
+                }
+            }
+        };
+        if let Err(e) = file.write_all(&line).await {
+            tracing::error!(job_id = id, "Could not save the outcome of a change: {e}");
+        }
+    }
+
+    /// Stop appending to the results of a finished job, and drop what only an unfinished job
+    /// needs. What the job did is kept.
+    pub async fn finish(&self, id: JobId) {
+        if let Some(results) = self.close_results(id) {
+            flush(id, &results).await;
+        }
+        let dir = self.job_dir(id);
+        remove_file(&dir.join(ENTRIES)).await;
+        remove_file(&dir.join(PLAN)).await;
+    }
+
+    /// Delete the files of a job that is forgotten.
+    pub async fn remove(&self, id: JobId) {
+        self.close_results(id);
+        let dir = self.job_dir(id);
+        if let Err(e) = tokio::fs::remove_dir_all(&dir).await
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            tracing::error!(job_id = id, "Could not delete {}: {e}", dir.display());
+        }
+    }
+
+    /// The entries of a job that has not planned its changes yet.
+    pub async fn entries(&self, id: JobId) -> Option<Vec<String>> {
+        read(self.job_dir(id).join(ENTRIES)).await
+    }
+
+    /// The changes of an unfinished job, once it has planned them.
+    pub async fn plan(&self, id: JobId) -> Option<Vec<Change>> {
+        read(self.job_dir(id).join(PLAN)).await
+    }
+
+    pub async fn references(&self, id: JobId) -> BTreeMap<String, String> {
+        read(self.job_dir(id).join(REFERENCES))
+            .await
+            .unwrap_or_default()
+    }
+
+    pub async fn conflicts(&self, id: JobId) -> Vec<Conflict> {
+        read(self.job_dir(id).join(CONFLICTS))
+            .await
+            .unwrap_or_default()
+    }
+
+    /// The outcome of every change the job sent, in the order they were saved.
+    pub async fn records(&self, id: JobId) -> Vec<Record> {
+        // what a running job has written may still be on its way to the file
+        let results = self
+            .open
+            .lock()
+            .expect("journal lock poisoned")
+            .get(&id)
+            .cloned();
+        if let Some(results) = results {
+            flush(id, &results).await;
+        }
+        let path = self.job_dir(id).join(RESULTS);
+        let read = tokio::task::spawn_blocking(move || match std::fs::read(&path) {
+            Ok(data) => parse_records(&data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                tracing::error!("Could not read {}: {e}", path.display());
+                Vec::new()
+            }
+        });
+        read.await.unwrap_or_default()
+    }
+}
+
+/// Let what was appended to the results of a job land in the file.
+async fn flush(id: JobId, results: &OpenResults) {
+    if let Some(file) = &mut *results.lock().await
+        && let Err(e) = file.flush().await
+    {
+        tracing::error!(job_id = id, "Could not save the outcome of a change: {e}");
+    }
+}
+
+/// Open a results file to append to it. A line cut short by the plugin stopping half way through
+/// writing it is ended first, so it doesn't swallow the next record.
+async fn open_results(path: &Path) -> std::io::Result<tokio::fs::File> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .await?;
+    if file.metadata().await?.len() > 0 {
+        let mut last = [0];
+        file.seek(std::io::SeekFrom::End(-1)).await?;
+        file.read_exact(&mut last).await?;
+        if last != *b"\n" {
+            file.write_all(b"\n").await?;
+        }
+    }
+    Ok(file)
+}
+
+/// Parse the lines of a results file. A line cut short by the plugin stopping half way through
+/// writing it is skipped.
+fn parse_records(data: &[u8]) -> Vec<Record> {
+    data.split(|&byte| byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| serde_json::from_slice(line).ok())
+        .collect()
+}
+
+/// Read a file of a job, `None` if it does not exist or can't be read.
+async fn read<T: DeserializeOwned + Send + 'static>(path: PathBuf) -> Option<T> {
+    let read = tokio::task::spawn_blocking(move || {
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                tracing::error!("Could not read {}: {e}", path.display());
+                return None;
+            }
+        };
+        serde_json::from_slice(&data)
+            .map_err(|e| tracing::error!("Could not read {}: {e}", path.display()))
+            .ok()
+    });
+    read.await.ok().flatten()
+}
+
+/// Write a file of a job, replacing it atomically.
+async fn write<T: Serialize + Send + 'static>(path: PathBuf, value: T) {
+    let write = tokio::task::spawn_blocking(move || {
+        let data = serde_json::to_vec(&value)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, &path)
+    });
+    if let Ok(Err(e)) = write.await {
+        tracing::error!("Could not save a job's files: {e}");
+    }
+}
+
+async fn remove_file(path: &Path) {
+    if let Err(e) = tokio::fs::remove_file(path).await
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        tracing::error!("Could not delete {}: {e}", path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::Action;
+    use crate::zoraxy_client::FailureKind;
+
+    fn journal(name: &str) -> Journal {
+        let dir = std::env::temp_dir().join(format!("journal-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        Journal::new(dir)
+    }
+
+    fn change(entry: &str) -> Change {
+        Change {
+            action: Action::Add,
+            entry: entry.to_string(),
+        }
+    }
+
+    fn applied(index: usize, entry: &str) -> Record {
+        Record {
+            index,
+            outcome: Outcome::Applied(change(entry)),
+        }
+    }
+
+    fn entries(records: &[Record]) -> Vec<(usize, String)> {
+        records
+            .iter()
+            .map(|record| match &record.outcome {
+                Outcome::Applied(change) => (record.index, change.entry.clone()),
+                Outcome::Failed(failure) => (record.index, format!("failed {}", failure.entry)),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn planning_replaces_the_entries_and_earlier_results() {
+        let journal = journal("plan");
+        let entries_in = vec!["10.0.0.0/8".to_string()];
+        journal
+            .create(1, entries_in.clone(), BTreeMap::new(), Vec::new())
+            .await;
+        assert!(journal.exists(1).await);
+        assert_eq!(journal.entries(1).await, Some(entries_in));
+        assert!(journal.plan(1).await.is_none());
+
+        journal.write_plan(1, vec![change("10.0.0.0/8")]).await;
+        journal.append(1, &applied(0, "10.0.0.0/8")).await;
+        journal.write_plan(1, vec![change("10.0.0.0/8")]).await;
+        assert!(journal.entries(1).await.is_none());
+        assert_eq!(journal.plan(1).await.unwrap().len(), 1);
+        assert!(journal.records(1).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn results_are_kept_in_order_after_the_job_finishes() {
+        let journal = journal("results");
+        journal
+            .create(1, Vec::new(), BTreeMap::new(), Vec::new())
+            .await;
+        journal
+            .write_plan(1, vec![change("1.1.1.1/32"), change("2.2.2.2/32")])
+            .await;
+        journal.append(1, &applied(1, "2.2.2.2/32")).await;
+        let failure = ImportFailure {
+            action: Action::Add,
+            entry: "1.1.1.1/32".to_string(),
+            kind: FailureKind::Rejected,
+            status: Some(400),
+            message: "rejected".to_string(),
+        };
+        let record = Record {
+            index: 0,
+            outcome: Outcome::Failed(failure),
+        };
+        journal.append(1, &record).await;
+        journal.finish(1).await;
+
+        assert!(journal.plan(1).await.is_none());
+        assert_eq!(
+            entries(&journal.records(1).await),
+            [
+                (1, "2.2.2.2/32".to_string()),
+                (0, "failed 1.1.1.1/32".to_string())
+            ]
+        );
+
+        journal.remove(1).await;
+        assert!(!journal.exists(1).await);
+        assert!(journal.records(1).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn creating_a_job_clears_files_left_by_an_earlier_one() {
+        let journal = journal("reused");
+        let conflict = Conflict {
+            entry: "10.0.0.0/8".to_string(),
+            safelisted: "10.1.1.1/32".to_string(),
+            reason: "safelist".to_string(),
+        };
+        journal
+            .create(
+                1,
+                Vec::new(),
+                BTreeMap::from([("1.1.1.1/32".to_string(), "SBL1".to_string())]),
+                vec![conflict],
+            )
+            .await;
+        journal.write_plan(1, vec![change("1.1.1.1/32")]).await;
+        journal.append(1, &applied(0, "1.1.1.1/32")).await;
+
+        // the state file forgot the job, and the id is handed out again
+        let journal = Journal::new(journal.dir.to_path_buf());
+        let entries_in = vec!["2.2.2.2/32".to_string()];
+        journal
+            .create(1, entries_in.clone(), BTreeMap::new(), Vec::new())
+            .await;
+        assert_eq!(journal.entries(1).await, Some(entries_in));
+        assert!(journal.plan(1).await.is_none());
+        assert!(journal.records(1).await.is_empty());
+        assert!(journal.references(1).await.is_empty());
+        assert!(journal.conflicts(1).await.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn jobs_append_their_results_side_by_side() {
+        let journal = journal("side-by-side");
+        for id in [1, 2] {
+            journal
+                .create(id, Vec::new(), BTreeMap::new(), Vec::new())
+                .await;
+        }
+        let mut workers = tokio::task::JoinSet::new();
+        for worker in 0..8usize {
+            let journal = journal.clone();
+            workers.spawn(async move {
+                for i in 0..25 {
+                    let index = worker * 25 + i;
+                    journal
+                        .append(worker as JobId % 2 + 1, &applied(index, "1.1.1.1/32"))
+                        .await;
+                }
+            });
+        }
+        workers.join_all().await;
+
+        for id in [1, 2] {
+            let mut indices: Vec<usize> = journal
+                .records(id)
+                .await
+                .iter()
+                .map(|record| record.index)
+                .collect();
+            indices.sort_unstable();
+            let expected: Vec<usize> = (0..200)
+                .filter(|index| (index / 25) % 2 + 1 == id as usize)
+                .collect();
+            assert_eq!(indices, expected);
+        }
+    }
+
+    #[test]
+    fn a_line_cut_short_is_skipped() {
+        let mut data = Vec::new();
+        for record in [applied(0, "1.1.1.1/32"), applied(1, "2.2.2.2/32")] {
+            serde_json::to_writer(&mut data, &record).unwrap();
+            data.push(b'\n');
+        }
+        data.extend_from_slice(br#"{"index":2,"appl"#);
+        assert_eq!(
+            entries(&parse_records(&data)),
+            [(0, "1.1.1.1/32".to_string()), (1, "2.2.2.2/32".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn results_appended_after_a_line_cut_short_are_kept() {
+        let journal = journal("cut-short");
+        journal
+            .create(1, Vec::new(), BTreeMap::new(), Vec::new())
+            .await;
+        journal.append(1, &applied(0, "1.1.1.1/32")).await;
+        journal.append(1, &applied(1, "2.2.2.2/32")).await;
+        journal.finish(1).await;
+
+        // the plugin stops half way through writing the second record
+        let path = journal.job_dir(1).join(RESULTS);
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() - 10]).unwrap();
+
+        let journal = Journal::new(journal.dir.to_path_buf());
+        journal.append(1, &applied(1, "2.2.2.2/32")).await;
+        assert_eq!(
+            entries(&journal.records(1).await),
+            [(0, "1.1.1.1/32".to_string()), (1, "2.2.2.2/32".to_string())]
+        );
+    }
+}
@@ -0,0 +1,467 @@
+//! JSON documents, like the responses of blocklist APIs, read with JSONPath-style selectors such as
+//! `$.data[*].ipAddress`.
+//!
+//! Selectors support `$` for the root, `.name` and `['name']` for a member, `[n]` for an array
+//! element (negative from the end), `*` and `[*]` for every element or member, and `..` to look at
+//! every depth.
+
+use serde_json::Value;
+
+use crate::parser::{ParsedList, RawEntry};
+use crate::validate::InvalidEntry;
+
+/// Which values of a JSON document are entries.
+///
+/// May be given as a single selector string, which is taken as `entries`.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(from = "SelectorInput")]
+pub struct JsonSelector {
+    /// Selects the IPs, or the objects holding them, like `$.data[*]`.
+    pub entries: String,
+    /// Path to the IPs within each selected object, like `ipAddress`. The selected values are the
+    /// IPs themselves if not given.
+    pub ip: Option<String>,
+    /// Paths within each selected object to values kept as the entry's reference, like
+    /// `countryCode`. A single field is kept as its value, several as `path=value` pairs.
+    pub fields: Vec<String>,
+}
+
+impl Default for JsonSelector {
+    /// A top-level array of IPs.
+    fn default() -> Self {
+        Self {
+            entries: "$[*]".to_string(),
+            ip: None,
+            fields: Vec::new(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum SelectorInput {
+    Entries(String),
+    Full {
+        entries: String,
+        #[serde(default)]
+        ip: Option<String>,
+        #[serde(default)]
+        fields: Vec<String>,
+    },
+}
+
+impl From<SelectorInput> for JsonSelector {
+    fn from(input: SelectorInput) -> Self {
+        match input {
+            SelectorInput::Entries(entries) => Self {
+                entries,
+                ..Self::default()
+            },
+            SelectorInput::Full {
+                entries,
+                ip,
+                fields,
+            } => Self {
+                entries,
+                ip,
+                fields,
+            },
+        }
+    }
+}
+
+impl JsonSelector {
+    pub fn validate(&self) -> Result<(), String> {
+        self.compile().map(|_| ())
+    }
+
+    fn compile(&self) -> Result<Compiled<'_>, String> {
+        let fields = self
+            .fields
+            .iter()
+            .map(|field| Ok((field.trim(), compile(field)?)))
+            .collect::<Result<_, String>>()?;
+        Ok(Compiled {
+            entries: compile(&self.entries)?,
+            ip: self.ip.as_deref().map(compile).transpose()?,
+            fields,
+        })
+    }
+}
+
+/// A [`JsonSelector`] whose paths have been parsed.
+struct Compiled<'a> {
+    entries: Vec<Step>,
+    ip: Option<Vec<Step>>,
+    fields: Vec<(&'a str, Vec<Step>)>,
+}
+
+/// One step of a selector path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Step {
+    Member(String),
+    /// An array element, counted from the end if negative.
+    Index(i64),
+    /// Every element of an array or member of an object.
+    Wildcard,
+    /// The value itself and everything nested in it, at any depth.
+    Descendants,
+}
+
+/// Read the entries of a JSON document picked by `selector`, a top-level array of IPs if there is
+/// none.
+///
+/// Each entry is numbered by the order it was selected in, as JSON has no lines to speak of.
+/// Selected arrays are taken element by element, and values that are not strings are reported as
+/// invalid.
+pub fn parse(input: &str, selector: Option<&JsonSelector>) -> Result<ParsedList, String> {
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+    let document: Value =
+        serde_json::from_str(input).map_err(|e| format!("not a valid JSON document: {e}"))?;
+    let default = JsonSelector::default();
+    let selector = selector.unwrap_or(&default);
+    let compiled = selector.compile()?;
+
+    let items = select(&document, &compiled.entries);
+    if items.is_empty() {
+        return Err(format!("`{}` does not select anything", selector.entries));
+    }
+    let mut list = ParsedList::default();
+    for (i, item) in items.into_iter().enumerate() {
+        let line = i + 1;
+        let values = match &compiled.ip {
+            Some(ip) => select(item, ip),
+            None => vec![item],
+        };
+        if values.is_empty() {
+            list.invalid.push(InvalidEntry {
+                line,
+                entry: item.to_string(),
+                reason: format!(
+                    "`{}` is not set",
+                    selector.ip.as_deref().unwrap_or_default()
+                ),
+            });
+            continue;
+        }
+        let reference = reference(item, &compiled.fields);
+        // an array of IPs counts as its elements
+        let values = values.into_iter().flat_map(|value| match value {
+            Value::Array(elements) => elements.iter().collect(),
+            value => vec![value],
+        });
+        for value in values {
+            match value {
+                Value::String(text) => list.entries.push(RawEntry {
+                    line,
+                    text: text.trim().to_string(),
+                    reference: reference.clone(),
+                }),
+                other => list.invalid.push(InvalidEntry {
+                    line,
+                    entry: other.to_string(),
+                    reason: "expected a string".to_string(),
+                }),
+            }
+        }
+    }
+    Ok(list)
+}
+
+/// The values of `fields` in `item`, as kept in the entry's reference.
+fn reference(item: &Value, fields: &[(&str, Vec<Step>)]) -> Option<String> {
+    let values: Vec<(&str, String)> = fields
+        .iter()
+        .flat_map(|(name, steps)| {
+            select(item, steps)
+                .into_iter()
+                .filter(|value| !value.is_null())
+                .map(|value| match value {
+                    Value::String(text) => (*name, text.clone()),
+                    other => (*name, other.to_string()),
+                })
+        })
+        .collect();
+    match values.as_slice() {
+        [] => None,
+        [(_, value)] if fields.len() == 1 => Some(value.clone()),
+        values => Some(
+            values
+                .iter()
+                .map(|(name, value)| format!("{name}={value}"))
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+    }
+}
+
+/// Every value `steps` lead to from `value`, in document order.
+fn select<'a>(value: &'a Value, steps: &[Step]) -> Vec<&'a Value> {
+    steps.iter().fold(vec![value], |values, step| {
+        values
+            .into_iter()
+            .flat_map(|value| apply(value, step))
+            .collect()
+    })
+}
+
+fn apply<'a>(value: &'a Value, step: &Step) -> Vec<&'a Value> {
+    match step {
+        Step::Member(name) => value.get(name).into_iter().collect(),
+        Step::Index(index) => {
+            let Value::Array(elements) = value else {
+                return Vec::new();
+            };
+            let index = match usize::try_from(*index) {
+                Ok(index) => Some(index),
+                Err(_) => elements.len().checked_sub(index.unsigned_abs() as usize),
+            };
+            index
+                .and_then(|index| elements.get(index))
+                .into_iter()
+                .collect()
+        }
+        Step::Wildcard => match value {
+            Value::Array(elements) => elements.iter().collect(),
+            Value::Object(members) => members.values().collect(),
+            _ => Vec::new(),
+        },
+        Step::Descendants => {
+            let mut values = Vec::new();
+            descendants(value, &mut values);
+            values
+        }
+    }
+}
+
+/// `value` and everything nested in it, in document order.
+fn descendants<'a>(value: &'a Value, values: &mut Vec<&'a Value>) {
+    values.push(value);
+    for nested in apply(value, &Step::Wildcard) {
+        descendants(nested, values);
+    }
+}
+
+/// Parse a selector path. A leading `$` or `@` is optional, so `data[*].ip` is read as
+/// `$.data[*].ip`.
+fn compile(path: &str) -> Result<Vec<Step>, String> {
+    let path = path.trim();
+    let rest = path.strip_prefix(['$', '@']).unwrap_or(path);
+    let rest = match rest.starts_with(['.', '[']) || rest.is_empty() {
+        true => rest.to_string(),
+        false => format!(".{rest}"),
+    };
+    let invalid = |reason: &str| format!("invalid selector `{path}`: {reason}");
+
+    let mut steps = Vec::new();
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '.' => {
+                if chars.peek() == Some(&'.') {
+                    chars.next();
+                    steps.push(Step::Descendants);
+                    // `..[0]` goes on with the bracket, `..name` with the name
+                    if chars.peek() == Some(&'[') {
+                        continue;
+                    }
+                }
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c == '.' || c == '[' {
+                        break;
+                    }
+                    name.push(c);
+                    chars.next();
+                }
+                match name.trim() {
+                    "" => return Err(invalid("a `.` must be followed by a name")),
+                    "*" => steps.push(Step::Wildcard),
+                    name => steps.push(Step::Member(name.to_string())),
+                }
+            }
+            '[' => {
+                let mut inner = String::new();
+                let mut quote = None;
+                loop {
+                    match chars.next() {
+                        None => return Err(invalid("a `[` is not closed")),
+                        Some(c) if Some(c) == quote => quote = None,
+                        Some(c @ ('\'' | '"')) if quote.is_none() && inner.trim().is_empty() => {
+                            quote = Some(c);
+                            inner.clear();
+                            inner.push(c);
+                        }
+                        Some(']') if quote.is_none() => break,
+                        Some(c) => inner.push(c),
+                    }
+                }
+                let inner = inner.trim();
+                let step = match inner.strip_prefix(['\'', '"']) {
+                    Some(name) => Step::Member(name.to_string()),
+                    None if inner == "*" => Step::Wildcard,
+                    None => Step::Index(
+                        inner
+                            .parse()
+                            .map_err(|_| invalid(&format!("`[{inner}]` is not an index")))?,
+                    ),
+                };
+                steps.push(step);
+            }
+            c => return Err(invalid(&format!("unexpected `{c}`"))),
+        }
+    }
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ABUSEIPDB: &str = r#"{
+        "meta": {"generatedAt": "2024-01-01T00:00:00+00:00"},
+        "data": [
+            {"ipAddress": "1.1.1.1", "countryCode": "US", "abuseConfidenceScore": 100},
+            {"ipAddress": "2.2.2.2", "countryCode": "CN", "abuseConfidenceScore": 90},
+            {"countryCode": "RU"}
+        ]
+    }"#;
+
+    fn selector(json: &str) -> JsonSelector {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn texts(list: &ParsedList) -> Vec<&str> {
+        list.entries
+            .iter()
+            .map(|entry| entry.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn reads_a_top_level_array_by_default() {
+        let list = parse(r#"["1.1.1.1", " 2.2.2.2 "]"#, None).unwrap();
+        assert_eq!(texts(&list), ["1.1.1.1", "2.2.2.2"]);
+        assert_eq!(list.entries[1].line, 2);
+    }
+
+    #[test]
+    fn a_string_is_taken_as_the_entries_selector() {
+        let selector = selector(r#""$.data[*].ipAddress""#);
+        assert_eq!(selector.entries, "$.data[*].ipAddress");
+        assert_eq!(selector.ip, None);
+        let list = parse(ABUSEIPDB, Some(&selector)).unwrap();
+        assert_eq!(texts(&list), ["1.1.1.1", "2.2.2.2"]);
+    }
+
+    #[test]
+    fn reads_ips_and_references_within_objects() {
+        let list = parse(
+            ABUSEIPDB,
+            Some(&selector(
+                r#"{"entries": "data[*]", "ip": "ipAddress", "fields": ["countryCode"]}"#,
+            )),
+        )
+        .unwrap();
+        assert_eq!(texts(&list), ["1.1.1.1", "2.2.2.2"]);
+        assert_eq!(list.entries[0].reference.as_deref(), Some("US"));
+        assert_eq!(list.invalid.len(), 1);
+        assert_eq!(list.invalid[0].line, 3);
+        assert_eq!(list.invalid[0].reason, "`ipAddress` is not set");
+    }
+
+    #[test]
+    fn several_fields_are_kept_as_pairs() {
+        let list = parse(
+            ABUSEIPDB,
+            Some(&selector(
+                r#"{"entries": "$.data[0]", "ip": "@.ipAddress",
+                    "fields": ["countryCode", "abuseConfidenceScore", "missing"]}"#,
+            )),
+        )
+        .unwrap();
+        assert_eq!(
+            list.entries[0].reference.as_deref(),
+            Some("countryCode=US, abuseConfidenceScore=100")
+        );
+    }
+
+    #[test]
+    fn arrays_of_ips_count_as_their_elements() {
+        let list = parse(
+            r#"{"groups": [{"ips": ["1.1.1.1", "2.2.2.2"]}, {"ips": [3, null]}]}"#,
+            Some(&selector(r#"{"entries": "groups[*]", "ip": "ips"}"#)),
+        )
+        .unwrap();
+        assert_eq!(texts(&list), ["1.1.1.1", "2.2.2.2"]);
+        let invalid: Vec<_> = list
+            .invalid
+            .iter()
+            .map(|entry| entry.entry.as_str())
+            .collect();
+        assert_eq!(invalid, ["3", "null"]);
+        assert!(list.invalid.iter().all(|entry| entry.line == 2));
+    }
+
+    #[test]
+    fn fails_when_nothing_is_selected() {
+        let error = parse(ABUSEIPDB, Some(&selector(r#""$.rows[*]""#))).unwrap_err();
+        assert_eq!(error, "`$.rows[*]` does not select anything");
+        assert!(parse("not json", None).is_err());
+    }
+
+    #[test]
+    fn compiles_every_kind_of_step() {
+        assert_eq!(compile("$").unwrap(), []);
+        assert_eq!(
+            compile("$.a['b.c'][\"d\"][2][-1][*].*").unwrap(),
+            [
+                Step::Member("a".to_string()),
+                Step::Member("b.c".to_string()),
+                Step::Member("d".to_string()),
+                Step::Index(2),
+                Step::Index(-1),
+                Step::Wildcard,
+                Step::Wildcard,
+            ]
+        );
+        assert_eq!(
+            compile("..ip").unwrap(),
+            [Step::Descendants, Step::Member("ip".to_string())]
+        );
+        assert_eq!(
+            compile("@..[0]").unwrap(),
+            [Step::Descendants, Step::Index(0)]
+        );
+        assert_eq!(compile("data").unwrap(), [Step::Member("data".to_string())]);
+        assert_eq!(compile("[']']").unwrap(), [Step::Member("]".to_string())]);
+    }
+
+    #[test]
+    fn rejects_malformed_selectors() {
+        for path in ["$.", "$.a.", "$[", "$[x]", "$[1", "$[0]x"] {
+            assert!(compile(path).is_err(), "{path} should be rejected");
+        }
+        let invalid = selector(r#"{"entries": "$[*]", "fields": ["a", "[b"]}"#);
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn selects_by_index_and_at_any_depth() {
+        let document: Value = serde_json::from_str(
+            r#"{"a": [{"ip": "1.1.1.1"}, {"nested": {"ip": "2.2.2.2"}}, {"ip": "3.3.3.3"}]}"#,
+        )
+        .unwrap();
+        let select = |path| -> Vec<String> {
+            select(&document, &compile(path).unwrap())
+                .into_iter()
+                .map(|value| value.as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(select("$..ip"), ["1.1.1.1", "2.2.2.2", "3.3.3.3"]);
+        assert_eq!(select("$.a[-1].ip"), ["3.3.3.3"]);
+        assert_eq!(select("$.a[0].ip"), ["1.1.1.1"]);
+        assert!(select("$.a[-4].ip").is_empty());
+        assert!(select("$.a[9].ip").is_empty());
+    }
+}
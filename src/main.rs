@@ -4,18 +4,52 @@ use std::sync::Arc;
 use anyhow::anyhow;
 use axum::Json;
 use axum::body::Body;
-use axum::extract::{Query, State};
+use axum::extract::{DefaultBodyLimit, Query, State};
 use axum::http::Request;
+use axum::response::{IntoResponse, Response};
 use axum::routing::{get, post};
 use axum::{Router, debug_handler};
 use reqwest::StatusCode;
-use tokio::sync::Mutex;
 use tracing::instrument;
 use zoraxy_rs::prelude::*;
 
 use crate::errors::Error;
-
+use crate::feeds::FeedRegistry;
+use crate::import_request::ImportRequest;
+use crate::jobs::JobRegistry;
+use crate::journal::Journal;
+use crate::provenance::ProvenanceRegistry;
+use crate::queue::JobQueue;
+use crate::rate_limit::RateLimiter;
+use crate::retry::CircuitBreaker;
+use crate::settings::{Settings, SharedSettings};
+use crate::store::Store;
+use crate::zoraxy_client::ZoraxyClient;
+
+mod csv;
+mod decompress;
 mod errors;
+mod events;
+mod feeds;
+mod firehol;
+mod import;
+mod import_request;
+mod jobs;
+mod journal;
+mod json;
+mod parser;
+mod plan;
+mod provenance;
+mod queue;
+mod rate_limit;
+mod retry;
+mod rollback;
+mod safelist;
+mod settings;
+mod spamhaus;
+mod store;
+mod validate;
+mod zoraxy_client;
 mod zoraxy_types;
 
 static WWW: include_dir::Dir = include_dir::include_dir!("www");
@@ -35,6 +69,14 @@ fn introspect() -> IntroSpect {
             PermittedApiEndpoint::new("POST", "/plugin/api/blacklist/ip/add")
                 .with_reason("Used to add IP addresses to the blocklist"),
         )
+        .add_permitted_api_endpoint(
+            PermittedApiEndpoint::new("POST", "/plugin/api/blacklist/ip/remove")
+                .with_reason("Used to remove IP addresses no longer in a synced blocklist"),
+        )
+        .add_permitted_api_endpoint(
+            PermittedApiEndpoint::new("GET", "/plugin/api/blacklist/list")
+                .with_reason("Used to skip IP addresses an Access Rule already blocks"),
+        )
         .add_permitted_api_endpoint(
             PermittedApiEndpoint::new("GET", "/plugin/api/access/list")
                 .with_reason("Used to list available access rulesets"),
@@ -43,11 +85,60 @@ fn introspect() -> IntroSpect {
 
 #[derive(Clone, Debug)]
 struct AppState {
-    pub api_key: String,
-    pub zoraxy_port: u16,
+    pub zoraxy: ZoraxyClient,
+    /// Client for fetching feeds, shared with `zoraxy`.
     pub reqwest_client: reqwest::Client,
-    // Only allow one import at a time, to avoid overwhelming the Zoraxy API.
-    pub importing_lock: Arc<Mutex<()>>,
+    // Imports wait here for their turn, to avoid overwhelming the Zoraxy API.
+    // Requests within an import are further bounded by `rate_limiter`.
+    pub queue: JobQueue,
+    pub jobs: JobRegistry,
+    pub feeds: FeedRegistry,
+    pub provenance: ProvenanceRegistry,
+    pub store: Store,
+    pub settings: SharedSettings,
+    pub breaker: Arc<CircuitBreaker>,
+    pub rate_limiter: Arc<RateLimiter>,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State of a plugin that keeps its files in a fresh temporary directory named after `name`.
+    ///
+    /// Its Zoraxy client points at a port nothing listens on, tests that need Zoraxy replace it
+    /// with one pointing at a stand-in.
+    fn for_tests(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("state-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = Store::new(dir.join(store::STATE_FILE));
+        let settings = Settings::default();
+        let reqwest_client = reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .expect("a client without a proxy builds");
+        Self {
+            zoraxy: ZoraxyClient::new(reqwest_client.clone(), String::new(), 0),
+            reqwest_client,
+            queue: JobQueue::default(),
+            jobs: JobRegistry::new(
+                jobs::Registry::default(),
+                Journal::new(dir.join(store::JOBS_DIR)),
+                store.changes.clone(),
+            ),
+            feeds: FeedRegistry::new(
+                feeds::Registry::default(),
+                store.changes.clone(),
+                dir.join(store::LISTS_DIR),
+            ),
+            provenance: ProvenanceRegistry::default(),
+            store,
+            rate_limiter: Arc::new(RateLimiter::new(
+                settings.throughput.requests_per_second,
+                settings.throughput.burst,
+            )),
+            settings: Arc::new(tokio::sync::RwLock::new(settings)),
+            breaker: Arc::new(CircuitBreaker::default()),
+        }
+    }
 }
 
 #[tokio::main]
@@ -64,20 +155,53 @@ async fn main() -> anyhow::Result<()> {
         .zoraxy_port
         .ok_or(anyhow!("missing Zoraxy Port in runtime configuration"))?;
     tracing::info!(
-        "API Call Example Plugin initialized with port: {}, zoraxy_port: {}",
+        "Blocklist Import Plugin initialized with port: {}, zoraxy_port: {}",
         runtime_cfg.port,
         zoraxy_port
     );
 
+    let reqwest_client = reqwest::Client::builder()
+        .user_agent("ZoraxyBlocklistImportPlugin/1.0")
+        .build()?;
+
+    let dir = std::env::current_dir()?;
+    let store = Store::new(dir.join(store::STATE_FILE));
+    let saved = store.load();
+    let lists_dir = dir.join(store::LISTS_DIR);
+    if let Err(e) = std::fs::create_dir_all(&lists_dir) {
+        tracing::error!("Could not create {}: {e}", lists_dir.display());
+    }
+    let settings = match saved.settings.validate() {
+        Ok(()) => saved.settings,
+        Err(e) => {
+            tracing::error!("Ignoring invalid saved settings: {e}");
+            Settings::default()
+        }
+    };
     let state = AppState {
-        api_key,
-        zoraxy_port,
-        reqwest_client: reqwest::Client::builder()
-            .user_agent("ZoraxyBlocklistImportPlugin/1.0")
-            .build()?,
-        importing_lock: Arc::new(Mutex::new(())),
+        zoraxy: ZoraxyClient::new(reqwest_client.clone(), api_key, zoraxy_port),
+        reqwest_client,
+        queue: JobQueue::default(),
+        jobs: JobRegistry::new(
+            saved.jobs,
+            Journal::new(dir.join(store::JOBS_DIR)),
+            store.changes.clone(),
+        ),
+        feeds: FeedRegistry::new(saved.feeds, store.changes.clone(), lists_dir),
+        provenance: ProvenanceRegistry::load(dir.join(store::PROVENANCE_FILE)),
+        store,
+        rate_limiter: Arc::new(RateLimiter::new(
+            settings.throughput.requests_per_second,
+            settings.throughput.burst,
+        )),
+        settings: Arc::new(tokio::sync::RwLock::new(settings)),
+        breaker: Arc::new(CircuitBreaker::default()),
     };
-    // let state = Arc::new(state);
+
+    tokio::spawn(store::run_saver(state.clone()));
+    import::resume_interrupted(&state).await;
+    tokio::spawn(queue::run_dispatcher(state.clone()));
+    tokio::spawn(feeds::run_scheduler(state.clone()));
 
     let ui_router = Arc::new(PluginUiRouter::new(&WWW, "/"));
 
@@ -87,7 +211,7 @@ async fn main() -> anyhow::Result<()> {
         .fallback(get(not_found_handler));
 
     let addr: SocketAddr = format!("127.0.0.1:{}", runtime_cfg.port).parse()?;
-    tracing::info!("API Call Example Plugin UI ready at http://{addr}");
+    tracing::info!("Blocklist Import Plugin UI ready at http://{addr}");
     start_plugin(app, state, addr, Some("/ui")).await
 }
 
@@ -97,14 +221,51 @@ async fn not_found_handler(req: Request<Body>) -> (StatusCode, String) {
     (StatusCode::NOT_FOUND, String::from("Not Found"))
 }
 
+/// Largest accepted import request body, uploads of big feeds easily exceed axum's default.
+const MAX_IMPORT_BODY_SIZE: usize = 64 * 1024 * 1024;
+
 fn rest_api_routes() -> Router<AppState> {
     Router::new()
-        .route("/api/import", post(handle_import_post))
+        .route(
+            "/api/import",
+            post(handle_import_post).layer(DefaultBodyLimit::max(MAX_IMPORT_BODY_SIZE)),
+        )
         .route("/api/list-access-rules", get(handle_list_access_rules))
         .route(
             "/api/list-blocklisted-ips",
             get(handle_list_blocklisted_ips),
         )
+        .route("/api/jobs", get(jobs::handle_list_jobs))
+        .route("/api/queue", get(queue::handle_list_queue))
+        .route("/api/jobs/events", get(events::handle_job_events))
+        .route("/api/jobs/{id}", get(jobs::handle_get_job))
+        .route(
+            "/api/jobs/{id}/failures",
+            get(jobs::handle_get_job_failures),
+        )
+        .route("/api/jobs/{id}/cancel", post(jobs::handle_cancel_job))
+        .route(
+            "/api/jobs/{id}/rollback",
+            post(rollback::handle_rollback_job),
+        )
+        .route(
+            "/api/jobs/{id}/conflicts",
+            get(jobs::handle_get_job_conflicts),
+        )
+        .route(
+            "/api/feeds",
+            get(feeds::handle_list_feeds).post(feeds::handle_create_feed),
+        )
+        .route(
+            "/api/feeds/{id}",
+            get(feeds::handle_get_feed).delete(feeds::handle_delete_feed),
+        )
+        .route("/api/feeds/{id}/refresh", post(feeds::handle_refresh_feed))
+        .route("/api/provenance", get(provenance::handle_get_provenance))
+        .route(
+            "/api/settings",
+            get(settings::handle_get_settings).put(settings::handle_put_settings),
+        )
 }
 
 #[derive(Clone, Debug, serde::Deserialize)]
@@ -117,88 +278,81 @@ pub struct IpsToBlacklist {
     pub ips: Vec<String>,
 }
 
-#[derive(Clone, Debug, serde::Deserialize)]
-pub struct ImportForm {
-    #[serde(rename = "access_rule_id")]
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ImportStarted {
+    #[serde(flatten)]
+    pub job: jobs::Job,
+    /// Entries that were skipped because they are not valid IPs or networks.
+    pub invalid_entries: Vec<validate::InvalidEntry>,
+    /// Entries that were skipped because they overlap the safelist.
+    pub safelisted_entries: Vec<safelist::Conflict>,
+}
+
+/// What an import would do, returned instead of starting a job when `dry_run` is set.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ImportPreview {
     pub access_rule_id: String,
-    #[serde(rename = "blocklist")]
-    // comma separated list of IPs
-    pub blocklist: String,
+    pub mode: plan::ImportMode,
+    /// What the list says about itself, if it says anything.
+    pub list: Option<Box<parser::ListMetadata>>,
+    /// Every valid entry of the list, normalized.
+    pub entries: Vec<String>,
+    pub invalid_entries: Vec<validate::InvalidEntry>,
+    pub safelisted_entries: Vec<safelist::Conflict>,
+    /// Rows of the list left out by the filters of the request.
+    pub filtered: usize,
+    pub duplicates: Vec<String>,
+    /// Entries merged into a larger network, or covered by another entry of the list.
+    pub aggregated: Vec<String>,
+    pub already_present: Vec<String>,
+    pub would_add: Vec<String>,
+    pub would_remove: Vec<String>,
 }
 
 #[debug_handler]
 async fn handle_import_post(
     State(ctx): State<AppState>,
-    // The form will contain access_rule_id and blocklist (comma separated IPs)
-    Query(form): Query<ImportForm>,
-) -> Result<String, Error> {
-    let ctx = ctx.clone();
-    // Parse the IPs from the blocklist textarea.
-    let ips: Vec<String> = form
-        .blocklist
-        .split(',')
-        .map(|s| s.trim().to_string())
-        .filter(|s| !s.is_empty())
-        .collect();
-
-    let client = ctx.reqwest_client.clone();
-    let url = format!(
-        "http://localhost:{}/plugin/api/blacklist/ip/add?id={}",
-        ctx.zoraxy_port, form.access_rule_id
-    );
-    let api_key = ctx.api_key.clone();
-
-    // write the response before moving things into the background task
-    let response = format!(
-        "Started import of {} IPs to Access Rule ID: {}, check logs for progress.",
-        ips.len(),
-        form.access_rule_id
-    );
-    tracing::info!("{response}");
-
-    if let Err(_) = ctx.importing_lock.try_lock() {
-        return Err(Error::ImportInProgress);
-    }
-
-    // spawn a task to import the IPs in the background
-    tokio::spawn(async move {
-        let import_lock = ctx.importing_lock.clone();
-        // Ensure only one import at a time.
-        // we want to fail instead of blocking here.
-        let Ok(import_lock) = import_lock.try_lock() else {
-            tracing::warn!("Import already in progress, rejecting new import request");
-            return;
+    // The request will contain the access_rule_id and the blocklist, as JSON, an upload or a query
+    request: ImportRequest,
+) -> Result<Response, Error> {
+    // Parse the IPs from the blocklist, invalid entries are reported rather than sent.
+    let validated = import::screen_entries(&ctx, request.list()?).await?;
+
+    if request.dry_run {
+        let existing = ctx
+            .zoraxy
+            .list_blacklisted_ips(&request.access_rule_id)
+            .await?;
+        let plan = plan::plan_import(&validated.valid, &existing, request.mode);
+        let canonical = |nets: &[ipnet::IpNet]| nets.iter().map(validate::canonical).collect();
+        let preview = ImportPreview {
+            access_rule_id: request.access_rule_id,
+            mode: request.mode,
+            list: validated.metadata,
+            entries: canonical(&validated.valid),
+            invalid_entries: validated.invalid,
+            safelisted_entries: validated.conflicts,
+            filtered: validated.filtered,
+            duplicates: canonical(&plan.duplicates),
+            aggregated: canonical(&plan.aggregated),
+            already_present: canonical(&plan.already_present),
+            would_add: canonical(&plan.to_add),
+            would_remove: plan.to_remove,
         };
+        return Ok((StatusCode::OK, Json(preview)).into_response());
+    }
 
-        // for each IP in payload.ips, add it to the Access Rule with ID form.access_rule_id
-        for (i, ip) in ips.iter().enumerate() {
-            tracing::info!(
-                "Importing IP {}/{} to Access Rule ID: {}",
-                i + 1,
-                ips.len(),
-                form.access_rule_id
-            );
-
-            if let Err(e) = client
-                .post(&url)
-                .query(&[("ip", ip)])
-                .bearer_auth(&api_key)
-                .send()
-                .await
-            {
-                tracing::warn!(
-                    access_rule_id = %form.access_rule_id,
-                    ip = %ip,
-                    error = %e,
-                    "Failed to import IP to Access Rule"
-                );
-                continue;
-            }
-        }
-        drop(import_lock); // release the lock
-    });
-
-    Ok(response)
+    let started = import::queue_import(
+        &ctx,
+        request.access_rule_id,
+        request.mode,
+        validated,
+        None,
+        request.priority,
+    )
+    .await;
+
+    Ok((StatusCode::ACCEPTED, Json(started)).into_response())
 }
 
 #[derive(Clone, Debug, serde::Serialize)]
@@ -210,15 +364,7 @@ pub struct ListAccessRulesResponse {
 async fn handle_list_access_rules(
     State(state): State<AppState>,
 ) -> Result<Json<Vec<zoraxy_types::AccessRule>>, Error> {
-    let client = state.reqwest_client.clone();
-    let url = format!(
-        "http://localhost:{}/plugin/api/access/list",
-        state.zoraxy_port
-    );
-    let api_key = state.api_key.clone();
-
-    let response = client.get(&url).bearer_auth(&api_key).send().await?;
-    let access_rules: Vec<zoraxy_types::AccessRule> = response.json().await?;
+    let access_rules = state.zoraxy.list_access_rules().await?;
 
     Ok(Json(access_rules))
 }
@@ -229,15 +375,7 @@ async fn handle_list_blocklisted_ips(
     State(state): State<AppState>,
     Query(query): Query<AccessRuleQuery>,
 ) -> Result<Json<Vec<String>>, Error> {
-    let client = state.reqwest_client.clone();
-    let url = format!(
-        "http://localhost:{}/plugin/api/blacklist/list?id={}&type=ip",
-        state.zoraxy_port, query.rule_id
-    );
-    let api_key = state.api_key.clone();
-
-    let response = client.get(&url).bearer_auth(&api_key).send().await?;
-    let blocklisted_ips: Vec<String> = response.json().await?;
+    let blocklisted_ips = state.zoraxy.list_blacklisted_ips(&query.rule_id).await?;
 
     Ok(Json(blocklisted_ips))
 }
@@ -0,0 +1,381 @@
+use crate::csv::{self, ColumnMapping};
+use crate::json::{self, JsonSelector};
+use crate::validate::InvalidEntry;
+use crate::{firehol, spamhaus};
+
+/// A candidate entry found in a blocklist, before any validation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawEntry {
+    /// 1-based line the entry was found on.
+    pub line: usize,
+    pub text: String,
+    /// The list's own reference for the entry, like a Spamhaus SBL ID.
+    pub reference: Option<String>,
+}
+
+/// The layout of a blocklist.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListFormat {
+    /// Recognize the layout from the list's contents.
+    #[default]
+    Auto,
+    /// IPs, networks and ranges, see [`parse_blocklist`].
+    Plain,
+    /// Spamhaus DROP, EDROP or DROPv6, see [`spamhaus::parse_drop`].
+    SpamhausDrop,
+    /// FireHOL `.netset` or `.ipset`, see [`firehol::parse_header`].
+    Firehol,
+    /// Comma separated values, read as told by [`ListOptions::columns`].
+    Csv,
+    /// Tab separated values, read as told by [`ListOptions::columns`].
+    Tsv,
+    /// A JSON document, read as told by [`ListOptions::selector`].
+    Json,
+}
+
+/// How to read the layouts that need to be told where the entries are.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ListOptions {
+    /// Where the entries of a CSV or TSV list are. Giving it implies one of those layouts.
+    pub columns: Option<ColumnMapping>,
+    /// Which values of a JSON document are entries. Giving it implies a JSON document.
+    pub selector: Option<JsonSelector>,
+    /// Files of a zip archive to import, by name or `*` and `?` pattern. Only needed if the
+    /// archive has more than one file.
+    pub members: Vec<String>,
+}
+
+impl ListOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(columns) = &self.columns {
+            columns.validate()?;
+        }
+        if let Some(selector) = &self.selector {
+            selector.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// What a list says about itself, as written in the list.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ListMetadata {
+    pub format: ListFormat,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub maintainer: Option<String>,
+    pub maintainer_url: Option<String>,
+    /// Where the maintainer got the list from.
+    pub source_url: Option<String>,
+    pub category: Option<String>,
+    pub version: Option<String>,
+    /// When the list was generated.
+    pub date: Option<String>,
+    /// How many entries the list says it has.
+    pub entries: Option<String>,
+}
+
+/// The candidate entries of a list, and what the list says about itself if it says anything.
+#[derive(Clone, Debug, Default)]
+pub struct ParsedList {
+    pub entries: Vec<RawEntry>,
+    /// Parts of the list that could not be read as an entry at all.
+    pub invalid: Vec<InvalidEntry>,
+    /// Rows left out by the filters of the request.
+    pub filtered: usize,
+    pub metadata: Option<Box<ListMetadata>>,
+}
+
+impl From<Vec<RawEntry>> for ParsedList {
+    fn from(entries: Vec<RawEntry>) -> Self {
+        Self {
+            entries,
+            ..Self::default()
+        }
+    }
+}
+
+/// Split `input` into candidate entries according to its `format`.
+///
+/// Fails if `options` don't fit the list, like a column the list does not have.
+pub fn parse_list(
+    input: &str,
+    format: ListFormat,
+    options: &ListOptions,
+) -> Result<ParsedList, String> {
+    let format = match format {
+        ListFormat::Auto if options.columns.is_some() => {
+            let first_line = input.lines().next().unwrap_or_default();
+            match first_line.contains('\t') {
+                true => ListFormat::Tsv,
+                false => ListFormat::Csv,
+            }
+        }
+        ListFormat::Auto if options.selector.is_some() => ListFormat::Json,
+        ListFormat::Auto if spamhaus::detect(input) => ListFormat::SpamhausDrop,
+        ListFormat::Auto if firehol::detect(input) => ListFormat::Firehol,
+        format => format,
+    };
+    let columns = options.columns.clone().unwrap_or_default();
+    Ok(match format {
+        ListFormat::Auto | ListFormat::Plain => parse_blocklist(input).into(),
+        ListFormat::SpamhausDrop => spamhaus::parse_drop(input).into(),
+        // the entries are plain, only the header needs reading
+        ListFormat::Firehol => ParsedList {
+            entries: parse_blocklist(input),
+            metadata: Some(Box::new(firehol::parse_header(input))),
+            ..ParsedList::default()
+        },
+        ListFormat::Csv => csv::parse(input, ',', &columns)?,
+        ListFormat::Tsv => csv::parse(input, '\t', &columns)?,
+        ListFormat::Json => json::parse(input, options.selector.as_ref())?,
+    })
+}
+
+/// Split `input` into candidate entries, dropping comments, annotations and empty lines.
+///
+/// Entries may be separated by newlines, commas, semicolons or any whitespace. `#` starts a
+/// comment that runs to the end of the line, as does a `;` at the start of a line. Words after an
+/// entry that can't be part of an address, like the `SBL123` of `1.2.3.0/24 ; SBL123` or the name
+/// of `1.2.3.4 scanner.example.com`, are annotations and left out, so annotated feeds can be
+/// imported as-is. Ranges may have spaces around the dash (`1.2.3.0 - 1.2.3.255`).
+pub fn parse_blocklist(input: &str) -> Vec<RawEntry> {
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+
+    input
+        .split('\n')
+        .enumerate()
+        .flat_map(|(i, line)| {
+            let tokens: Vec<&str> = strip_comment(line)
+                .split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+                .filter(|token| !token.is_empty())
+                .collect();
+            let mut after_entry = false;
+            join_ranges(&tokens)
+                .into_iter()
+                .filter(|token| {
+                    let address = looks_like_address(token);
+                    let annotation = after_entry && !address;
+                    after_entry |= address;
+                    !annotation
+                })
+                .map(|token| RawEntry {
+                    line: i + 1,
+                    text: token,
+                    reference: None,
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// The part of `line` before any comment.
+fn strip_comment(line: &str) -> &str {
+    let line = line.split('#').next().unwrap_or_default();
+    match line.trim_start().starts_with(';') {
+        true => "",
+        false => line,
+    }
+}
+
+/// Whether `token` is shaped like an IP, network or range, valid or not. An IPv4 address is made
+/// of digits and dots, an IPv6 address of hex digits and at least two colons, so words like
+/// `cafe.de` or `add` don't count.
+fn looks_like_address(token: &str) -> bool {
+    token.split('-').all(|part| {
+        let (addr, prefix) = part.split_once('/').unwrap_or((part, ""));
+        let ipv4 = addr.contains('.') && addr.chars().all(|c| c.is_ascii_digit() || c == '.');
+        let ipv6 = addr.matches(':').count() >= 2
+            && addr
+                .chars()
+                .all(|c| c.is_ascii_hexdigit() || matches!(c, ':' | '.'));
+        (ipv4 || ipv6) && prefix.chars().all(|c| c.is_ascii_digit())
+    })
+}
+
+/// Join the halves of ranges written with whitespace around the dash (`1.2.3.0 - 1.2.3.255`) back
+/// into single tokens. A dash that isn't between two addresses, like that of `1.2.3.4 - scanner`,
+/// is left alone.
+fn join_ranges(tokens: &[&str]) -> Vec<String> {
+    let mut joined = Vec::with_capacity(tokens.len());
+    let mut rest = tokens;
+    while let [first, ..] = rest {
+        let (token, used) = match rest {
+            [start, "-", end, ..] if looks_like_address(start) && looks_like_address(end) => {
+                (format!("{start}-{end}"), 3)
+            }
+            [start, end, ..]
+                if start.strip_suffix('-').is_some_and(looks_like_address)
+                    && looks_like_address(end)
+                    || looks_like_address(start)
+                        && end.strip_prefix('-').is_some_and(looks_like_address) =>
+            {
+                (format!("{start}{end}"), 2)
+            }
+            _ => (first.to_string(), 1),
+        };
+        joined.push(token);
+        rest = &rest[used..];
+    }
+    joined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(entries: &[RawEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.text.as_str()).collect()
+    }
+
+    #[test]
+    fn splits_on_newlines_commas_semicolons_and_whitespace() {
+        let entries = parse_blocklist("1.1.1.1,2.2.2.2;3.3.3.3\t4.4.4.4\r\n5.5.5.5  6.6.6.6\n");
+        assert_eq!(
+            texts(&entries),
+            [
+                "1.1.1.1", "2.2.2.2", "3.3.3.3", "4.4.4.4", "5.5.5.5", "6.6.6.6"
+            ]
+        );
+    }
+
+    #[test]
+    fn numbers_entries_by_line() {
+        let entries = parse_blocklist("\u{feff}1.1.1.1\n\n# comment\n2.2.2.2 3.3.3.3");
+        let lines: Vec<usize> = entries.iter().map(|entry| entry.line).collect();
+        assert_eq!(lines, [1, 4, 4]);
+    }
+
+    #[test]
+    fn drops_comments() {
+        let entries = parse_blocklist("# header\n1.1.1.1 # trailing\n; comment 9.9.9.9\n  ; too");
+        assert_eq!(texts(&entries), ["1.1.1.1"]);
+    }
+
+    #[test]
+    fn semicolons_after_whitespace_still_separate_entries() {
+        let entries = parse_blocklist("1.2.3.4 ; 5.6.7.8");
+        assert_eq!(texts(&entries), ["1.2.3.4", "5.6.7.8"]);
+    }
+
+    #[test]
+    fn drops_annotations_after_an_entry() {
+        let entries = parse_blocklist(
+            "1.2.3.0/24 ; SBL123\n1.2.3.4 scanner.example.com\n5.6.7.8 seen 2024-01-01 9.9.9.9",
+        );
+        assert_eq!(
+            texts(&entries),
+            ["1.2.3.0/24", "1.2.3.4", "5.6.7.8", "9.9.9.9"]
+        );
+    }
+
+    #[test]
+    fn keeps_words_that_are_not_after_an_entry() {
+        // reported as invalid later on, rather than silently dropped
+        let entries = parse_blocklist("hostname 1.2.3.4\n1.2.3.x");
+        assert_eq!(texts(&entries), ["hostname", "1.2.3.4", "1.2.3.x"]);
+    }
+
+    #[test]
+    fn words_of_hex_letters_are_not_addresses() {
+        let entries = parse_blocklist("1.2.3.4 cafe.de bad.beef\n2001:db8::1 add 12.34.56.78");
+        assert_eq!(texts(&entries), ["1.2.3.4", "2001:db8::1", "12.34.56.78"]);
+        assert!(!looks_like_address("cafe.de"));
+        assert!(!looks_like_address("add"));
+        assert!(!looks_like_address("dead:beef"));
+        assert!(looks_like_address("::ffff:1.2.3.4/128"));
+        assert!(looks_like_address("1.2.3.0-1.2.3.255"));
+    }
+
+    #[test]
+    fn keeps_malformed_addresses_after_an_entry() {
+        let entries = parse_blocklist("1.2.3.4 999.1.1.1 2001:db8::12345");
+        assert_eq!(texts(&entries), ["1.2.3.4", "999.1.1.1", "2001:db8::12345"]);
+    }
+
+    #[test]
+    fn joins_ranges_with_spaces_around_the_dash() {
+        let entries = parse_blocklist("1.2.3.0 - 1.2.3.255, 2001:db8::1 -2001:db8::ff");
+        assert_eq!(
+            texts(&entries),
+            ["1.2.3.0-1.2.3.255", "2001:db8::1-2001:db8::ff"]
+        );
+        let entries = parse_blocklist("1.2.3.0- 1.2.3.255\n10.0.0.0 -10.0.0.9");
+        assert_eq!(texts(&entries), ["1.2.3.0-1.2.3.255", "10.0.0.0-10.0.0.9"]);
+    }
+
+    #[test]
+    fn a_dash_before_a_comment_is_not_a_range() {
+        let entries =
+            parse_blocklist("1.2.3.4 - scanner\n5.6.7.8 -bad host\n9.9.9.9 - 2001:db8::1");
+        assert_eq!(
+            texts(&entries),
+            ["1.2.3.4", "5.6.7.8", "9.9.9.9-2001:db8::1"]
+        );
+    }
+
+    #[test]
+    fn detects_spamhaus_and_firehol_lists() {
+        let options = ListOptions::default();
+        let drop = parse_list(
+            "; Spamhaus DROP\n1.2.3.0/24 ; SBL1\n",
+            ListFormat::Auto,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(drop.entries[0].reference.as_deref(), Some("SBL1"));
+
+        let netset = "#\n# firehol_level1\n#\n# ipv4 hash:net ipset\n#\n1.2.3.0/24\n";
+        let firehol = parse_list(netset, ListFormat::Auto, &options).unwrap();
+        let metadata = firehol.metadata.unwrap();
+        assert_eq!(metadata.format, ListFormat::Firehol);
+        assert_eq!(metadata.name.as_deref(), Some("firehol_level1"));
+        assert_eq!(texts(&firehol.entries), ["1.2.3.0/24"]);
+    }
+
+    #[test]
+    fn plain_lists_are_not_mistaken_for_other_layouts() {
+        let list = parse_list(
+            "1.1.1.1\n2.2.2.2\n",
+            ListFormat::Auto,
+            &ListOptions::default(),
+        )
+        .unwrap();
+        assert!(list.metadata.is_none());
+        assert_eq!(texts(&list.entries), ["1.1.1.1", "2.2.2.2"]);
+    }
+
+    #[test]
+    fn options_imply_csv_tsv_and_json() {
+        let columns = ListOptions {
+            columns: Some(serde_json::from_str(r#"{"ip": 1, "header": false}"#).unwrap()),
+            ..ListOptions::default()
+        };
+        let csv = parse_list("a,1.1.1.1\n", ListFormat::Auto, &columns).unwrap();
+        assert_eq!(texts(&csv.entries), ["1.1.1.1"]);
+        let tsv = parse_list("a\t2.2.2.2\n", ListFormat::Auto, &columns).unwrap();
+        assert_eq!(texts(&tsv.entries), ["2.2.2.2"]);
+
+        let selector = ListOptions {
+            selector: Some(serde_json::from_str(r#""$.ips[*]""#).unwrap()),
+            ..ListOptions::default()
+        };
+        let json = parse_list(r#"{"ips": ["3.3.3.3"]}"#, ListFormat::Auto, &selector).unwrap();
+        assert_eq!(texts(&json.entries), ["3.3.3.3"]);
+    }
+
+    #[test]
+    fn an_explicit_format_wins_over_detection() {
+        let list = parse_list(
+            "1.2.3.0/24 ; SBL1\n",
+            ListFormat::Plain,
+            &ListOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(list.entries[0].reference, None);
+        assert_eq!(texts(&list.entries), ["1.2.3.0/24"]);
+    }
+}
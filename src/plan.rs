@@ -0,0 +1,251 @@
+use std::collections::HashSet;
+
+use ipnet::IpNet;
+
+use crate::validate::{canonical, parse_entry};
+
+/// How an import treats entries of the Access Rule that are not in the submitted list.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportMode {
+    /// Only add new entries, leaving everything else in place.
+    #[default]
+    Add,
+    /// Make the Access Rule mirror the list, removing entries that are no longer in it.
+    Sync,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Add,
+    Remove,
+}
+
+impl Action {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Add => "add",
+            Action::Remove => "remove",
+        }
+    }
+}
+
+/// A single call an import makes to Zoraxy.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Change {
+    pub action: Action,
+    pub entry: String,
+}
+
+/// What an import has to change in an Access Rule to apply a list of entries.
+///
+/// The list is first collapsed into the fewest networks that cover it, so overlapping and adjacent
+/// entries become a single larger network.
+#[derive(Clone, Debug, Default)]
+pub struct ImportPlan {
+    /// Networks not yet in the Access Rule, in address order.
+    pub to_add: Vec<IpNet>,
+    /// Networks the Access Rule already has. Outside of [`ImportMode::Sync`] this includes
+    /// networks covered by a larger one in the Access Rule.
+    pub already_present: Vec<IpNet>,
+    /// Entries that appeared more than once in the submitted list, counted once per repeat.
+    pub duplicates: Vec<IpNet>,
+    /// Entries of the list that were merged into a larger network with other entries, or that
+    /// another entry already covers.
+    pub aggregated: Vec<IpNet>,
+    /// Entries of the Access Rule that are not in the list, exactly as Zoraxy stores them.
+    /// Only filled in [`ImportMode::Sync`].
+    pub to_remove: Vec<String>,
+}
+
+impl ImportPlan {
+    /// The calls to make to Zoraxy, additions first so a sync that is cut short never leaves the
+    /// rule with stale entries removed and their replacements missing.
+    pub fn changes(&self) -> Vec<Change> {
+        let removals = self.to_remove.iter().map(|entry| Change {
+            action: Action::Remove,
+            entry: entry.clone(),
+        });
+        let additions = self.to_add.iter().map(|net| Change {
+            action: Action::Add,
+            entry: canonical(net),
+        });
+        additions.chain(removals).collect()
+    }
+}
+
+/// Compare the submitted `entries` against the `existing` blacklist of an Access Rule.
+///
+/// Existing entries are normalized the same way as submitted ones, so `::ffff:1.2.3.4` in
+/// Zoraxy matches `1.2.3.4` in the list.
+pub fn plan_import(entries: &[IpNet], existing: &[String], mode: ImportMode) -> ImportPlan {
+    let mut plan = ImportPlan::default();
+
+    let mut seen = HashSet::with_capacity(entries.len());
+    let mut unique = Vec::with_capacity(entries.len());
+    for &net in entries {
+        if seen.insert(net) {
+            unique.push(net);
+        } else {
+            plan.duplicates.push(net);
+        }
+    }
+    let networks = IpNet::aggregate(&unique);
+    let kept: HashSet<IpNet> = networks.iter().copied().collect();
+    plan.aggregated = unique
+        .into_iter()
+        .filter(|net| !kept.contains(net))
+        .collect();
+
+    let existing: HashSet<IpNet> = existing
+        .iter()
+        .filter_map(|raw| {
+            let net = parse_entry(raw.trim()).ok();
+            // entries Zoraxy has that we can't make sense of are left alone, even when syncing
+            if mode == ImportMode::Sync && net.is_some_and(|net| !kept.contains(&net)) {
+                plan.to_remove.push(raw.clone());
+            }
+            net
+        })
+        .collect();
+
+    for net in networks {
+        let present = match mode {
+            // in a sync everything not in the list is removed, so a larger network of the
+            // Access Rule can't stand in for the ones it covers
+            ImportMode::Sync => existing.contains(&net),
+            ImportMode::Add => is_covered(net, &existing),
+        };
+        if present {
+            plan.already_present.push(net);
+        } else {
+            plan.to_add.push(net);
+        }
+    }
+    plan
+}
+
+/// Whether `net`, or a larger network containing it, is one of `networks`.
+fn is_covered(net: IpNet, networks: &HashSet<IpNet>) -> bool {
+    std::iter::successors(Some(net), IpNet::supernet).any(|net| networks.contains(&net))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(texts: &[&str]) -> Vec<IpNet> {
+        texts.iter().map(|text| text.parse().unwrap()).collect()
+    }
+
+    fn strings(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    #[test]
+    fn adds_only_what_is_missing() {
+        let plan = plan_import(
+            &nets(&["1.1.1.1/32", "2.2.2.2/32"]),
+            &strings(&["1.1.1.1", "9.9.9.9"]),
+            ImportMode::Add,
+        );
+        assert_eq!(plan.to_add, nets(&["2.2.2.2/32"]));
+        assert_eq!(plan.already_present, nets(&["1.1.1.1/32"]));
+        assert!(plan.to_remove.is_empty());
+    }
+
+    #[test]
+    fn counts_duplicates_once_per_repeat() {
+        let plan = plan_import(
+            &nets(&["1.1.1.1/32", "1.1.1.1/32", "1.1.1.1/32"]),
+            &[],
+            ImportMode::Add,
+        );
+        assert_eq!(plan.to_add, nets(&["1.1.1.1/32"]));
+        assert_eq!(plan.duplicates.len(), 2);
+    }
+
+    #[test]
+    fn aggregates_overlapping_and_adjacent_networks() {
+        let plan = plan_import(
+            &nets(&["10.0.0.0/25", "10.0.0.128/25", "10.0.0.5/32", "10.0.2.0/24"]),
+            &[],
+            ImportMode::Add,
+        );
+        assert_eq!(plan.to_add, nets(&["10.0.0.0/24", "10.0.2.0/24"]));
+        assert_eq!(
+            plan.aggregated,
+            nets(&["10.0.0.0/25", "10.0.0.128/25", "10.0.0.5/32"])
+        );
+    }
+
+    #[test]
+    fn matches_existing_entries_after_normalizing_them() {
+        let plan = plan_import(
+            &nets(&["1.2.3.4/32", "10.0.0.0/8"]),
+            &strings(&["::ffff:1.2.3.4", " 10.0.0.0/8 "]),
+            ImportMode::Add,
+        );
+        assert!(plan.to_add.is_empty());
+        assert_eq!(plan.already_present.len(), 2);
+    }
+
+    #[test]
+    fn a_larger_existing_network_covers_entries_when_adding() {
+        let entries = nets(&["10.1.2.3/32"]);
+        let existing = strings(&["10.0.0.0/8"]);
+        let plan = plan_import(&entries, &existing, ImportMode::Add);
+        assert!(plan.to_add.is_empty());
+
+        // but not when syncing, as the larger network is removed
+        let plan = plan_import(&entries, &existing, ImportMode::Sync);
+        assert_eq!(plan.to_add, entries);
+        assert_eq!(plan.to_remove, existing);
+    }
+
+    #[test]
+    fn sync_removes_what_the_list_no_longer_has() {
+        let plan = plan_import(
+            &nets(&["1.1.1.1/32", "2.2.2.2/32"]),
+            &strings(&["1.1.1.1", "3.3.3.3", "::ffff:4.4.4.4", "not an ip"]),
+            ImportMode::Sync,
+        );
+        assert_eq!(plan.to_add, nets(&["2.2.2.2/32"]));
+        // removed exactly as Zoraxy has them, and entries it can't read are left alone
+        assert_eq!(plan.to_remove, strings(&["3.3.3.3", "::ffff:4.4.4.4"]));
+    }
+
+    #[test]
+    fn sync_keeps_entries_the_list_aggregates_to() {
+        let plan = plan_import(
+            &nets(&["10.0.0.0/25", "10.0.0.128/25"]),
+            &strings(&["10.0.0.0/24", "10.0.0.0/25"]),
+            ImportMode::Sync,
+        );
+        assert!(plan.to_add.is_empty());
+        assert_eq!(plan.to_remove, strings(&["10.0.0.0/25"]));
+    }
+
+    #[test]
+    fn additions_come_before_removals() {
+        let plan = plan_import(
+            &nets(&["2.2.2.2/32", "2001:db8::/32"]),
+            &strings(&["3.3.3.3"]),
+            ImportMode::Sync,
+        );
+        let changes: Vec<(Action, String)> = plan
+            .changes()
+            .into_iter()
+            .map(|change| (change.action, change.entry))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (Action::Add, "2.2.2.2".to_string()),
+                (Action::Add, "2001:db8::/32".to_string()),
+                (Action::Remove, "3.3.3.3".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use axum::extract::{Query, State};
+use axum::{Json, debug_handler};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::RwLock;
+
+use crate::AppState;
+use crate::feeds::FeedId;
+use crate::jobs::{JobId, unix_now};
+use crate::plan::{Action, Change};
+use crate::validate::{canonical, parse_entry};
+
+/// Lines the log may hold beyond twice its entries before it is compacted, so a small log isn't
+/// rewritten all the time.
+const COMPACT_SLACK: usize = 1000;
+
+/// Where an entry of an Access Rule came from.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Provenance {
+    /// The job that added the entry.
+    pub job_id: JobId,
+    /// The feed the entry was fetched from, if it was not imported by hand.
+    pub feed_id: Option<FeedId>,
+    /// The list's own reference for the entry, like a Spamhaus SBL ID.
+    pub reference: Option<String>,
+    pub added_at: u64,
+}
+
+/// Provenance of the entries the plugin added, by Access Rule and then by entry as it was sent to
+/// Zoraxy.
+#[derive(Clone, Debug, Default)]
+struct Registry {
+    rules: BTreeMap<String, BTreeMap<String, Provenance>>,
+}
+
+/// A line of the provenance log, setting or clearing the provenance of one entry.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+struct Line {
+    rule: String,
+    entry: String,
+    /// `None` once the entry was removed from the Access Rule.
+    added: Option<Provenance>,
+}
+
+impl Registry {
+    fn apply(&mut self, line: Line) {
+        match line.added {
+            Some(provenance) => {
+                self.rules
+                    .entry(line.rule)
+                    .or_default()
+                    .insert(line.entry, provenance);
+            }
+            None => {
+                if let Some(rule) = self.rules.get_mut(&line.rule) {
+                    rule.remove(&line.entry);
+                    if rule.is_empty() {
+                        self.rules.remove(&line.rule);
+                    }
+                }
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.rules.values().map(BTreeMap::len).sum()
+    }
+
+    /// The log that sets up this provenance from scratch.
+    fn to_log(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (rule, entries) in &self.rules {
+            for (entry, provenance) in entries {
+                let line = Line {
+                    rule: rule.clone(),
+                    entry: entry.clone(),
+                    added: Some(provenance.clone()),
+                };
+                serde_json::to_writer(&mut data, &line)
+                    .expect("a provenance line always serializes");
+                data.push(b'\n');
+            }
+        }
+        data
+    }
+
+    /// Replay a provenance log. A last line cut short by the plugin stopping half way through
+    /// writing it is skipped, any other line that can't be parsed fails the whole log.
+    fn from_log(data: &[u8]) -> Result<Self, String> {
+        let mut registry = Self::default();
+        let mut lines = data
+            .split(|&byte| byte == b'\n')
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .peekable();
+        while let Some((number, line)) = lines.next() {
+            match serde_json::from_slice(line) {
+                Ok(line) => registry.apply(line),
+                Err(_) if lines.peek().is_none() && !data.ends_with(b"\n") => {}
+                Err(e) => return Err(format!("line {}: {e}", number + 1)),
+            }
+        }
+        Ok(registry)
+    }
+
+    /// Write the provenance to a new log at `path`, replacing it atomically.
+    fn write_log(&self, path: &Path) -> std::io::Result<()> {
+        let tmp = path.with_extension("jsonl.tmp");
+        std::fs::write(&tmp, self.to_log())?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Log {
+    registry: Registry,
+    /// Lines in the log file, which grows past the entries it describes as they are removed and
+    /// added again.
+    lines: usize,
+    file: Option<tokio::fs::File>,
+}
+
+/// Shared record of which job, and which feed, put each entry into an Access Rule.
+///
+/// Every change is appended to a log file, which is compacted once it has grown to more than
+/// twice the entries it describes.
+#[derive(Clone, Debug, Default)]
+pub struct ProvenanceRegistry {
+    inner: Arc<RwLock<Log>>,
+    /// `None` keeps the provenance in memory only.
+    path: Option<Arc<PathBuf>>,
+}
+
+impl ProvenanceRegistry {
+    /// Read the provenance log at `path`, compacting it.
+    ///
+    /// A missing log is a fresh install. A log that can't be read is moved aside, so it is not
+    /// overwritten, and the provenance starts from scratch.
+    pub fn load(path: PathBuf) -> Self {
+        let loaded = match std::fs::read(&path) {
+            Ok(data) => Registry::from_log(&data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Registry::default()),
+            Err(e) => Err(e.to_string()),
+        };
+        let registry = match loaded {
+            Ok(registry) => registry,
+            Err(e) => {
+                let aside = path.with_extension(format!("jsonl.unreadable-{}", unix_now()));
+                tracing::error!(
+                    "Could not load provenance from {}, moving it to {}: {e}",
+                    path.display(),
+                    aside.display()
+                );
+                if let Err(e) = std::fs::rename(&path, &aside) {
+                    tracing::error!(
+                        "Could not move {} aside, provenance will not be saved: {e}",
+                        path.display()
+                    );
+                    return Self::default();
+                }
+                Registry::default()
+            }
+        };
+        let file = registry
+            .write_log(&path)
+            .and_then(|()| std::fs::OpenOptions::new().append(true).open(&path));
+        let file = match file {
+            Ok(file) => Some(tokio::fs::File::from_std(file)),
+            Err(e) => {
+                tracing::error!("Could not save provenance to {}: {e}", path.display());
+                None
+            }
+        };
+        Self {
+            inner: Arc::new(RwLock::new(Log {
+                lines: registry.len(),
+                registry,
+                file,
+            })),
+            path: Some(Arc::new(path)),
+        }
+    }
+
+    /// Note a change that was applied to an Access Rule.
+    pub async fn record(
+        &self,
+        access_rule_id: &str,
+        change: &Change,
+        job_id: JobId,
+        feed_id: Option<FeedId>,
+        reference: Option<String>,
+    ) {
+        let line = Line {
+            rule: access_rule_id.to_string(),
+            entry: change.entry.clone(),
+            added: match change.action {
+                Action::Add => Some(Provenance {
+                    job_id,
+                    feed_id,
+                    reference,
+                    added_at: unix_now(),
+                }),
+                Action::Remove => None,
+            },
+        };
+        let mut log = self.inner.write().await;
+        self.append(&mut log, line).await;
+    }
+
+    /// Forget the provenance of entries that are no longer in an Access Rule, which holds
+    /// `existing`, because they were removed outside the plugin.
+    pub async fn retain(&self, access_rule_id: &str, existing: &[String]) {
+        let normalize = |entry: &str| {
+            parse_entry(entry.trim())
+                .map_or_else(|_| entry.trim().to_string(), |net| canonical(&net))
+        };
+        let present: HashSet<String> = existing.iter().map(|entry| normalize(entry)).collect();
+
+        let mut log = self.inner.write().await;
+        let gone: Vec<String> = log
+            .registry
+            .rules
+            .get(access_rule_id)
+            .into_iter()
+            .flat_map(BTreeMap::keys)
+            .filter(|entry| !present.contains(&normalize(entry)))
+            .cloned()
+            .collect();
+        for entry in gone {
+            let line = Line {
+                rule: access_rule_id.to_string(),
+                entry,
+                added: None,
+            };
+            self.append(&mut log, line).await;
+        }
+    }
+
+    async fn append(&self, log: &mut Log, line: Line) {
+        let mut data = serde_json::to_vec(&line).expect("a provenance line always serializes");
+        data.push(b'\n');
+        log.registry.apply(line);
+        log.lines += 1;
+
+        if log.lines > 2 * log.registry.len() + COMPACT_SLACK {
+            self.compact(log).await;
+        } else if let Some(file) = &mut log.file
+            && let Err(e) = file.write_all(&data).await
+        {
+            tracing::error!("Could not save provenance: {e}");
+        }
+    }
+
+    /// Rewrite the log with only the provenance it ends up with.
+    async fn compact(&self, log: &mut Log) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        // let what was appended land before the file is replaced
+        if let Some(mut file) = log.file.take()
+            && let Err(e) = file.flush().await
+        {
+            tracing::error!("Could not save provenance: {e}");
+        }
+        let registry = log.registry.clone();
+        let path = Arc::clone(path);
+        let write = tokio::task::spawn_blocking(move || {
+            registry.write_log(&path)?;
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(path.as_path())
+        });
+        match write.await {
+            Ok(Ok(file)) => {
+                log.file = Some(tokio::fs::File::from_std(file));
+                log.lines = log.registry.len();
+            }
+            Ok(Err(e)) => tracing::error!("Could not save provenance: {e}"),
+            Err(e) => tracing::error!("Could not save provenance: {e}"),
+        }
+    }
+
+    /// Provenance of every entry the plugin added to an Access Rule.
+    pub async fn for_rule(&self, access_rule_id: &str) -> BTreeMap<String, Provenance> {
+        self.inner
+            .read()
+            .await
+            .registry
+            .rules
+            .get(access_rule_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Write out whatever the log file still buffers.
+    #[cfg(test)]
+    pub(crate) async fn flush(&self) {
+        if let Some(file) = &mut self.inner.write().await.file {
+            file.flush().await.unwrap();
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct ProvenanceQuery {
+    pub rule_id: String,
+}
+
+/// Entries of an Access Rule the plugin added, and where each came from. Entries added outside the
+/// plugin are not listed.
+#[debug_handler]
+pub async fn handle_get_provenance(
+    State(state): State<AppState>,
+    Query(query): Query<ProvenanceQuery>,
+) -> Json<BTreeMap<String, Provenance>> {
+    Json(state.provenance.for_rule(&query.rule_id).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("provenance-{name}-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn change(action: Action, entry: &str) -> Change {
+        Change {
+            action,
+            entry: entry.to_string(),
+        }
+    }
+
+    async fn record(registry: &ProvenanceRegistry, action: Action, entry: &str) {
+        let change = change(action, entry);
+        registry.record("rule", &change, 1, None, None).await;
+    }
+
+    #[tokio::test]
+    async fn the_log_is_replayed_on_load() {
+        let path = log_path("replay");
+        let registry = ProvenanceRegistry::load(path.clone());
+        record(&registry, Action::Add, "1.1.1.1/32").await;
+        record(&registry, Action::Add, "2.2.2.2/32").await;
+        record(&registry, Action::Remove, "1.1.1.1/32").await;
+        registry.flush().await;
+
+        let loaded = ProvenanceRegistry::load(path.clone());
+        let entries: Vec<String> = loaded.for_rule("rule").await.into_keys().collect();
+        assert_eq!(entries, ["2.2.2.2/32"]);
+        // loading compacts the log
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn the_log_is_compacted_once_it_outgrows_its_entries() {
+        let path = log_path("compact");
+        let registry = ProvenanceRegistry::load(path.clone());
+        for _ in 0..COMPACT_SLACK {
+            record(&registry, Action::Add, "1.1.1.1/32").await;
+            record(&registry, Action::Remove, "1.1.1.1/32").await;
+        }
+        record(&registry, Action::Add, "2.2.2.2/32").await;
+        registry.flush().await;
+
+        let lines = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert!(lines <= COMPACT_SLACK, "{lines} lines");
+        let loaded = ProvenanceRegistry::load(path);
+        let entries: Vec<String> = loaded.for_rule("rule").await.into_keys().collect();
+        assert_eq!(entries, ["2.2.2.2/32"]);
+    }
+
+    #[tokio::test]
+    async fn a_log_that_cant_be_read_is_moved_aside() {
+        let dir =
+            std::env::temp_dir().join(format!("provenance-unreadable-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("provenance.jsonl");
+        let history = "not a provenance line\n{\"rule\":\"rule\"}\n";
+        std::fs::write(&path, history).unwrap();
+
+        let registry = ProvenanceRegistry::load(path.clone());
+        assert!(registry.for_rule("rule").await.is_empty());
+        record(&registry, Action::Add, "1.1.1.1/32").await;
+        registry.flush().await;
+
+        let aside: Vec<PathBuf> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|entry| *entry != path)
+            .collect();
+        assert_eq!(aside.len(), 1);
+        assert_eq!(std::fs::read_to_string(&aside[0]).unwrap(), history);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn only_a_last_line_cut_short_is_skipped() {
+        let line = r#"{"rule":"rule","entry":"1.1.1.1/32","added":null}"#;
+        assert!(Registry::from_log(format!("{line}\n{{\"rule\":\"ru").as_bytes()).is_ok());
+        assert!(Registry::from_log(format!("{{\"rule\":\"ru\n{line}\n").as_bytes()).is_err());
+    }
+
+    #[tokio::test]
+    async fn entries_removed_outside_the_plugin_are_forgotten() {
+        let registry = ProvenanceRegistry::default();
+        record(&registry, Action::Add, "1.1.1.1/32").await;
+        record(&registry, Action::Add, "10.0.0.0/8").await;
+        registry
+            .retain("rule", &["10.0.0.0/8".to_string(), "3.3.3.3".to_string()])
+            .await;
+        let entries: Vec<String> = registry.for_rule("rule").await.into_keys().collect();
+        assert_eq!(entries, ["10.0.0.0/8"]);
+
+        registry.retain("rule", &[]).await;
+        assert!(registry.inner.read().await.registry.rules.is_empty());
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::State;
+use axum::{Json, debug_handler};
+use tokio::sync::Notify;
+
+use crate::AppState;
+use crate::import;
+use crate::jobs::{Job, JobId};
+
+/// How urgently a queued job should run. Jobs of the same priority run in the order they were
+/// queued.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Scheduled feed refreshes, which can wait for imports someone is looking at.
+    Low,
+    #[default]
+    Normal,
+    /// Rollbacks, which undo a mistake that is already in effect.
+    High,
+}
+
+#[derive(Clone, Debug)]
+struct Pending {
+    job_id: JobId,
+    priority: Priority,
+    access_rule_id: String,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    /// Jobs waiting to run, in the order they should be started.
+    pending: Vec<Pending>,
+    /// Access Rules a job is running for, only one job per rule runs at a time.
+    busy_rules: HashSet<String>,
+}
+
+/// Jobs waiting for their turn to run, started by [`run_dispatcher`].
+#[derive(Clone, Debug, Default)]
+pub struct JobQueue {
+    inner: Arc<Mutex<Queue>>,
+    wake: Arc<Notify>,
+}
+
+impl JobQueue {
+    /// Queue a job behind every job of the same or a higher priority.
+    pub fn push(&self, job: &Job) {
+        let mut queue = self.inner.lock().expect("queue lock poisoned");
+        let position = queue
+            .pending
+            .iter()
+            .position(|pending| pending.priority < job.priority)
+            .unwrap_or(queue.pending.len());
+        queue.pending.insert(
+            position,
+            Pending {
+                job_id: job.id,
+                priority: job.priority,
+                access_rule_id: job.access_rule_id.clone(),
+            },
+        );
+        drop(queue);
+        self.wake();
+    }
+
+    /// Take a job off the queue before it starts. Returns whether it was still waiting.
+    pub fn remove(&self, id: JobId) -> bool {
+        let mut queue = self.inner.lock().expect("queue lock poisoned");
+        let before = queue.pending.len();
+        queue.pending.retain(|pending| pending.job_id != id);
+        queue.pending.len() != before
+    }
+
+    /// Have the dispatcher look for jobs to start again.
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    /// IDs of the jobs waiting to run, next first.
+    pub fn pending(&self) -> Vec<JobId> {
+        let queue = self.inner.lock().expect("queue lock poisoned");
+        queue.pending.iter().map(|pending| pending.job_id).collect()
+    }
+
+    /// Take the first job whose Access Rule is free, if fewer than `max_running` jobs run.
+    fn take_next(&self, max_running: usize) -> Option<Slot> {
+        let mut queue = self.inner.lock().expect("queue lock poisoned");
+        if queue.busy_rules.len() >= max_running {
+            return None;
+        }
+        let position = queue
+            .pending
+            .iter()
+            .position(|pending| !queue.busy_rules.contains(&pending.access_rule_id))?;
+        let next = queue.pending.remove(position);
+        queue.busy_rules.insert(next.access_rule_id.clone());
+        Some(Slot {
+            queue: self.clone(),
+            job_id: next.job_id,
+            access_rule_id: next.access_rule_id,
+        })
+    }
+}
+
+/// A running job's claim on its Access Rule, given back when the job is done.
+struct Slot {
+    queue: JobQueue,
+    job_id: JobId,
+    access_rule_id: String,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        // also reached if the job panicked, so the rule is never stuck
+        self.queue
+            .inner
+            .lock()
+            .expect("queue lock poisoned")
+            .busy_rules
+            .remove(&self.access_rule_id);
+        self.queue.wake();
+    }
+}
+
+/// Start queued jobs as soon as they may run, for as long as the plugin runs.
+///
+/// Jobs for different Access Rules run side by side, up to
+/// [`crate::settings::ThroughputSettings::concurrent_jobs`] at once.
+pub async fn run_dispatcher(ctx: AppState) {
+    loop {
+        let max_running = ctx.settings.read().await.throughput.concurrent_jobs;
+        while let Some(slot) = ctx.queue.take_next(max_running) {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                import::run_job(&ctx, slot.job_id).await;
+                drop(slot);
+            });
+        }
+        ctx.queue.wake.notified().await;
+    }
+}
+
+/// The jobs waiting to run, next first.
+#[debug_handler]
+pub async fn handle_list_queue(State(state): State<AppState>) -> Json<Vec<Job>> {
+    let mut jobs = Vec::new();
+    for id in state.queue.pending() {
+        jobs.extend(state.jobs.get(id).await);
+    }
+    Json(jobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan::ImportMode;
+
+    fn job(id: JobId, access_rule_id: &str, priority: Priority) -> Job {
+        let mut job = Job::new(
+            id,
+            access_rule_id.to_string(),
+            ImportMode::default(),
+            0,
+            0,
+            0,
+            None,
+        );
+        job.priority = priority;
+        job
+    }
+
+    /// Take every job that may start now, returning their slots.
+    fn take_all(queue: &JobQueue, max_running: usize) -> Vec<Slot> {
+        std::iter::from_fn(|| queue.take_next(max_running)).collect()
+    }
+
+    fn ids(slots: &[Slot]) -> Vec<JobId> {
+        slots.iter().map(|slot| slot.job_id).collect()
+    }
+
+    #[test]
+    fn jobs_run_by_priority_then_in_the_order_they_were_queued() {
+        let queue = JobQueue::default();
+        queue.push(&job(1, "a", Priority::Normal));
+        queue.push(&job(2, "b", Priority::Low));
+        queue.push(&job(3, "c", Priority::High));
+        queue.push(&job(4, "d", Priority::Normal));
+        queue.push(&job(5, "e", Priority::High));
+        queue.push(&job(6, "f", Priority::Low));
+        assert_eq!(queue.pending(), [3, 5, 1, 4, 2, 6]);
+        assert_eq!(ids(&take_all(&queue, usize::MAX)), [3, 5, 1, 4, 2, 6]);
+    }
+
+    #[test]
+    fn only_one_job_runs_per_access_rule() {
+        let queue = JobQueue::default();
+        queue.push(&job(1, "a", Priority::Normal));
+        queue.push(&job(2, "a", Priority::High));
+        queue.push(&job(3, "b", Priority::Normal));
+
+        let running = take_all(&queue, usize::MAX);
+        assert_eq!(ids(&running), [2, 3]);
+        assert_eq!(queue.pending(), [1]);
+
+        // the rule is free again once its job is done
+        let mut running = running.into_iter();
+        drop(running.next());
+        assert_eq!(ids(&take_all(&queue, usize::MAX)), [1]);
+        assert!(queue.pending().is_empty());
+    }
+
+    #[test]
+    fn no_more_than_the_concurrent_jobs_limit_run() {
+        let queue = JobQueue::default();
+        for (id, rule) in [(1, "a"), (2, "b"), (3, "c")] {
+            queue.push(&job(id, rule, Priority::Normal));
+        }
+        let running = take_all(&queue, 2);
+        assert_eq!(ids(&running), [1, 2]);
+        assert!(queue.take_next(2).is_none());
+
+        drop(running);
+        assert_eq!(ids(&take_all(&queue, 2)), [3]);
+    }
+
+    #[test]
+    fn a_removed_job_is_not_started() {
+        let queue = JobQueue::default();
+        queue.push(&job(1, "a", Priority::Normal));
+        queue.push(&job(2, "b", Priority::Normal));
+        assert!(queue.remove(1));
+        assert!(!queue.remove(1));
+        assert_eq!(ids(&take_all(&queue, usize::MAX)), [2]);
+    }
+}
@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+#[derive(Debug)]
+struct Bucket {
+    /// Tokens added per second, `0.0` disables rate limiting.
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled_at = now;
+    }
+}
+
+/// Token bucket limiting how many requests per second are sent to Zoraxy, across all imports.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate: requests_per_second,
+                burst,
+                tokens: burst,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Change the rate and burst size, keeping the tokens currently available.
+    pub async fn reconfigure(&self, requests_per_second: f64, burst: u32) {
+        let mut bucket = self.bucket.lock().await;
+        bucket.refill(Instant::now());
+        bucket.rate = requests_per_second;
+        bucket.burst = f64::from(burst.max(1));
+        bucket.tokens = bucket.tokens.min(bucket.burst);
+    }
+
+    /// Wait until a request may be sent.
+    ///
+    /// The bucket is not held while waiting, so other workers and [`RateLimiter::reconfigure`]
+    /// are not held up: once the wait is over the bucket is checked again.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().await;
+                if bucket.rate <= 0.0 {
+                    return;
+                }
+                bucket.refill(Instant::now());
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / bucket.rate)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Acquire `count` tokens, returning how long it took.
+    async fn acquire(limiter: &RateLimiter, count: usize) -> Duration {
+        let start = Instant::now();
+        for _ in 0..count {
+            limiter.acquire().await;
+        }
+        start.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_burst_goes_through_at_once_then_tokens_refill_at_the_rate() {
+        let limiter = RateLimiter::new(2.0, 3);
+        assert_eq!(acquire(&limiter, 3).await, Duration::ZERO);
+        assert_eq!(acquire(&limiter, 1).await, Duration::from_millis(500));
+        assert_eq!(acquire(&limiter, 2).await, Duration::from_secs(1));
+
+        // tokens refill while idle, up to the burst size
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(acquire(&limiter, 3).await, Duration::ZERO);
+        assert_eq!(acquire(&limiter, 1).await, Duration::from_millis(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_rate_of_zero_does_not_limit() {
+        let limiter = RateLimiter::new(0.0, 1);
+        assert_eq!(acquire(&limiter, 100).await, Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconfiguring_applies_to_waiting_requests() {
+        let limiter = std::sync::Arc::new(RateLimiter::new(0.1, 1));
+        limiter.acquire().await;
+
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { acquire(&limiter, 1).await }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // the waiting request must not hold the bucket, or this would wait along with it
+        let reconfigured = Instant::now();
+        limiter.reconfigure(1.0, 1).await;
+        assert_eq!(reconfigured.elapsed(), Duration::ZERO);
+
+        // the first wait of 10s runs out, the faster rate has filled the bucket by then
+        assert_eq!(waiting.await.unwrap(), Duration::from_secs(10));
+        assert_eq!(acquire(&limiter, 1).await, Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconfiguring_caps_the_tokens_at_the_new_burst() {
+        let limiter = RateLimiter::new(1.0, 10);
+        limiter.reconfigure(1.0, 2).await;
+        assert_eq!(acquire(&limiter, 2).await, Duration::ZERO);
+        assert_eq!(acquire(&limiter, 1).await, Duration::from_secs(1));
+    }
+}
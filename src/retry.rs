@@ -0,0 +1,165 @@
+use std::hash::{BuildHasher, RandomState};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::settings::{CircuitBreakerSettings, RetrySettings};
+
+/// Delay before retry number `attempt` (starting at 1), using exponential backoff with jitter.
+///
+/// The delay is drawn uniformly from the upper half of the exponential backoff, so concurrent
+/// retries spread out without any of them retrying immediately.
+pub fn backoff_delay(settings: &RetrySettings, attempt: u32) -> Duration {
+    let exponent = attempt.saturating_sub(1).min(32) as i32;
+    let backoff_ms = (settings.initial_backoff_ms as f64 * settings.multiplier.powi(exponent))
+        .min(settings.max_backoff_ms as f64) as u64;
+
+    let half = backoff_ms / 2;
+    let jitter = if half == 0 {
+        0
+    } else {
+        RandomState::new().hash_one(attempt) % (half + 1)
+    };
+    Duration::from_millis(backoff_ms - half + jitter)
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Circuit breaker shared by every import talking to Zoraxy.
+///
+/// The circuit opens after a number of changes in a row still failed transiently once their
+/// retries ran out, during which imports pause rather than sending requests. Once the cooldown
+/// elapses the next change acts as a probe: a success closes the circuit, another failure opens it
+/// again.
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    /// The instant the circuit closes again, if it is currently open.
+    pub fn open_until(&self) -> Option<Instant> {
+        let state = self.state.lock().expect("circuit breaker lock poisoned");
+        state.open_until.filter(|until| *until > Instant::now())
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().expect("circuit breaker lock poisoned");
+        state.consecutive_failures = 0;
+        state.open_until = None;
+    }
+
+    /// Count a request that still failed transiently after its retries, opening the circuit once
+    /// there have been enough in a row.
+    pub fn record_failure(&self, settings: &CircuitBreakerSettings) {
+        let mut state = self.state.lock().expect("circuit breaker lock poisoned");
+        state.consecutive_failures += 1;
+        if state.consecutive_failures < settings.failure_threshold {
+            return;
+        }
+
+        let until = Instant::now() + Duration::from_secs(settings.cooldown_secs);
+        // another worker may already have opened it, keep the later deadline
+        state.open_until = Some(state.open_until.map_or(until, |current| current.max(until)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(failure_threshold: u32) -> CircuitBreakerSettings {
+        CircuitBreakerSettings {
+            failure_threshold,
+            ..CircuitBreakerSettings::default()
+        }
+    }
+
+    #[test]
+    fn opens_after_threshold_failures_in_a_row() {
+        let breaker = CircuitBreaker::default();
+        let settings = settings(3);
+        breaker.record_failure(&settings);
+        breaker.record_failure(&settings);
+        assert!(breaker.open_until().is_none());
+        breaker.record_failure(&settings);
+        assert!(breaker.open_until().is_some());
+    }
+
+    #[test]
+    fn success_resets_the_count() {
+        let breaker = CircuitBreaker::default();
+        let settings = settings(2);
+        breaker.record_failure(&settings);
+        breaker.record_success();
+        breaker.record_failure(&settings);
+        assert!(breaker.open_until().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn probes_once_the_cooldown_elapses() {
+        let breaker = CircuitBreaker::default();
+        let settings = CircuitBreakerSettings {
+            failure_threshold: 2,
+            cooldown_secs: 30,
+            ..CircuitBreakerSettings::default()
+        };
+        breaker.record_failure(&settings);
+        breaker.record_failure(&settings);
+        let until = breaker.open_until().unwrap();
+        assert_eq!(until - Instant::now(), Duration::from_secs(30));
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(breaker.open_until().is_none());
+        // a failing probe opens the circuit again straight away
+        breaker.record_failure(&settings);
+        assert_eq!(
+            breaker.open_until().unwrap() - Instant::now(),
+            Duration::from_secs(30)
+        );
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        // a successful probe closes it, and it takes the whole threshold to open it again
+        breaker.record_success();
+        breaker.record_failure(&settings);
+        assert!(breaker.open_until().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_the_later_deadline() {
+        let breaker = CircuitBreaker::default();
+        breaker.record_failure(&CircuitBreakerSettings {
+            failure_threshold: 1,
+            cooldown_secs: 60,
+            ..CircuitBreakerSettings::default()
+        });
+        breaker.record_failure(&CircuitBreakerSettings {
+            failure_threshold: 1,
+            cooldown_secs: 10,
+            ..CircuitBreakerSettings::default()
+        });
+        assert_eq!(
+            breaker.open_until().unwrap() - Instant::now(),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn backoff_stays_within_bounds() {
+        let settings = RetrySettings::default();
+        for attempt in 1..10 {
+            let full = (settings.initial_backoff_ms as f64 * settings.multiplier.powi(attempt - 1))
+                .min(settings.max_backoff_ms as f64) as u64;
+            let delay = backoff_delay(&settings, attempt as u32).as_millis() as u64;
+            assert!(
+                (full - full / 2..=full).contains(&delay),
+                "{delay} for {full}"
+            );
+        }
+    }
+}
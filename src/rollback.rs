@@ -0,0 +1,330 @@
+use std::collections::{BTreeMap, HashSet};
+
+use axum::extract::{Path, State};
+use axum::response::{IntoResponse, Response};
+use axum::{Json, debug_handler};
+use reqwest::StatusCode;
+
+use crate::AppState;
+use crate::errors::Error;
+use crate::import;
+use crate::jobs::{Job, JobId, JobState};
+use crate::plan::{Action, Change};
+use crate::provenance::Provenance;
+use crate::safelist::{Conflict, Safelist};
+use crate::validate::{Validated, canonical, parse_entry};
+
+/// What rolling back a job does to its Access Rule.
+#[derive(Debug, Default)]
+struct RollbackPlan {
+    /// The changes that undo the job, last change first.
+    changes: Vec<Change>,
+    /// Changes of the job left alone because the Access Rule has moved on since.
+    skipped: Vec<Change>,
+    /// Removed entries that are not added back because they overlap the safelist.
+    conflicts: Vec<Conflict>,
+}
+
+/// Plan undoing the `applied` changes of job `job_id` to an Access Rule that now holds
+/// `existing`, whose entries came from `provenance`.
+///
+/// An added entry is only removed again if it is still in the Access Rule and no later job added
+/// it since, and a removed entry is only added back if it is not in the Access Rule already and
+/// does not overlap the `safelist` as it is now.
+fn plan_rollback(
+    job_id: JobId,
+    applied: Vec<Change>,
+    existing: &[String],
+    provenance: &BTreeMap<String, Provenance>,
+    safelist: &Safelist,
+) -> RollbackPlan {
+    let normalize = |entry: &str| {
+        parse_entry(entry.trim()).map_or_else(|_| entry.trim().to_string(), |net| canonical(&net))
+    };
+    let present: HashSet<String> = existing.iter().map(|entry| normalize(entry)).collect();
+
+    let mut changes = Vec::new();
+    let mut skipped = Vec::new();
+    // undo the last change first
+    for change in applied.into_iter().rev() {
+        let is_present = present.contains(&normalize(&change.entry));
+        let undo = match change.action {
+            Action::Add => {
+                is_present
+                    && provenance
+                        .get(&change.entry)
+                        .is_some_and(|provenance| provenance.job_id == job_id)
+            }
+            Action::Remove => !is_present,
+        };
+        if undo {
+            changes.push(Change {
+                action: match change.action {
+                    Action::Add => Action::Remove,
+                    Action::Remove => Action::Add,
+                },
+                entry: change.entry,
+            });
+        } else {
+            skipped.push(change);
+        }
+    }
+
+    let mut additions = Validated {
+        valid: changes
+            .iter()
+            .filter(|change| change.action == Action::Add)
+            .filter_map(|change| parse_entry(&change.entry).ok())
+            .collect(),
+        ..Validated::default()
+    };
+    safelist.screen(&mut additions);
+    let conflicts = additions.conflicts;
+    let safelisted: HashSet<&str> = conflicts.iter().map(|c| c.entry.as_str()).collect();
+    changes.retain(|change| {
+        change.action == Action::Remove || !safelisted.contains(normalize(&change.entry).as_str())
+    });
+
+    RollbackPlan {
+        changes,
+        skipped,
+        conflicts,
+    }
+}
+
+/// Work out and make the changes of rollback job `job`, recording progress in the job registry.
+///
+/// The changes are planned against the Access Rule as it is when the job runs, not when it was
+/// queued.
+pub async fn run_rollback(ctx: &AppState, job: Job) {
+    let job_id = job.id;
+    ctx.jobs.mark_running(job_id).await;
+    let settings = ctx.settings.read().await.clone();
+
+    let original = match job.rollback_of {
+        Some(id) => ctx.jobs.get(id).await,
+        None => None,
+    };
+    let Some(original) = original else {
+        ctx.jobs
+            .mark_finished(
+                job_id,
+                JobState::Failed,
+                Some("The job to roll back no longer exists".to_string()),
+            )
+            .await;
+        return;
+    };
+    let existing = match ctx.zoraxy.list_blacklisted_ips(&job.access_rule_id).await {
+        Ok(existing) => existing,
+        Err(failure) => {
+            tracing::error!(job_id, error = %failure.message, "Failed to read current blacklist");
+            ctx.jobs
+                .mark_finished(
+                    job_id,
+                    JobState::Failed,
+                    Some(format!(
+                        "Could not read the Access Rule's current blacklist: {}",
+                        failure.message
+                    )),
+                )
+                .await;
+            return;
+        }
+    };
+    let applied = ctx.jobs.applied(original.id).await.unwrap_or_default();
+    let provenance = ctx.provenance.for_rule(&original.access_rule_id).await;
+    let safelist = Safelist::new(&settings.safelist);
+    let RollbackPlan {
+        changes,
+        skipped,
+        conflicts,
+    } = plan_rollback(original.id, applied, &existing, &provenance, &safelist);
+    if !conflicts.is_empty() {
+        tracing::warn!(
+            job_id,
+            conflicts = conflicts.len(),
+            "Held back re-additions that overlap the safelist"
+        );
+        ctx.jobs.record_conflicts(job_id, &conflicts).await;
+    }
+    ctx.jobs
+        .update(job_id, |job| {
+            job.already_present = skipped.len();
+            job.to_remove = changes
+                .iter()
+                .filter(|change| change.action == Action::Remove)
+                .count();
+            job.total = changes.len();
+        })
+        .await;
+    ctx.jobs.checkpoint_plan(job_id, &changes).await;
+    tracing::info!(
+        job_id,
+        rollback_of = original.id,
+        changes = changes.len(),
+        skipped = skipped.len(),
+        "Planned rollback of Access Rule ID: {}",
+        job.access_rule_id
+    );
+
+    let changes = changes.into_iter().enumerate().collect();
+    import::apply_changes(ctx, settings, job_id, &job.access_rule_id, changes).await;
+}
+
+/// Undo the changes a finished job made to its Access Rule, as a new job.
+#[debug_handler]
+pub async fn handle_rollback_job(
+    State(ctx): State<AppState>,
+    Path(id): Path<JobId>,
+) -> Result<Response, Error> {
+    let job = ctx.jobs.get(id).await.ok_or(Error::JobNotFound(id))?;
+    if job.finished_at.is_none() {
+        return Err(Error::JobNotFinished(id));
+    }
+    // a rollback that did not go through may be tried again
+    if let Some(by) = job.rolled_back_by {
+        let rollback = ctx.jobs.get(by).await;
+        if rollback.is_some_and(|rollback| {
+            !matches!(rollback.state, JobState::Failed | JobState::Cancelled)
+        }) {
+            return Err(Error::AlreadyRolledBack { job: id, by });
+        }
+    }
+    let applied = ctx.jobs.applied(id).await.unwrap_or_default();
+    if applied.is_empty() {
+        return Err(Error::NothingToRollBack(id));
+    }
+
+    let rollback = ctx.jobs.create_rollback(&job, applied.len()).await;
+    ctx.queue.push(&rollback);
+    tracing::info!(
+        job_id = rollback.id,
+        rollback_of = id,
+        "Queued rollback of {} changes to Access Rule ID: {}",
+        applied.len(),
+        job.access_rule_id
+    );
+    Ok((StatusCode::ACCEPTED, Json(rollback)).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::SafelistSettings;
+
+    fn change(action: Action, entry: &str) -> Change {
+        Change {
+            action,
+            entry: entry.to_string(),
+        }
+    }
+
+    fn added_by(job_id: JobId) -> Provenance {
+        Provenance {
+            job_id,
+            feed_id: None,
+            reference: None,
+            added_at: 0,
+        }
+    }
+
+    fn strings(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    fn no_safelist() -> Safelist {
+        Safelist::new(&SafelistSettings {
+            entries: Vec::new(),
+            reserved_ranges: false,
+        })
+    }
+
+    #[test]
+    fn changes_are_undone_last_first() {
+        let applied = vec![
+            change(Action::Add, "1.1.1.1"),
+            change(Action::Remove, "2.2.2.2"),
+            change(Action::Add, "10.0.0.0/8"),
+        ];
+        let provenance = BTreeMap::from([
+            ("1.1.1.1".to_string(), added_by(1)),
+            ("10.0.0.0/8".to_string(), added_by(1)),
+        ]);
+        // Zoraxy may list an entry in another form than the one the plugin sent
+        let existing = strings(&["1.1.1.1/32", "10.0.0.0/8"]);
+        let plan = plan_rollback(1, applied, &existing, &provenance, &no_safelist());
+        assert_eq!(
+            plan.changes,
+            [
+                change(Action::Remove, "10.0.0.0/8"),
+                change(Action::Add, "2.2.2.2"),
+                change(Action::Remove, "1.1.1.1"),
+            ]
+        );
+        assert!(plan.skipped.is_empty());
+        assert!(plan.conflicts.is_empty());
+    }
+
+    #[test]
+    fn entries_a_later_job_added_again_are_kept() {
+        let applied = vec![
+            change(Action::Add, "1.1.1.1"),
+            change(Action::Add, "3.3.3.3"),
+        ];
+        let provenance = BTreeMap::from([
+            ("1.1.1.1".to_string(), added_by(2)),
+            ("3.3.3.3".to_string(), added_by(1)),
+        ]);
+        let existing = strings(&["1.1.1.1", "3.3.3.3"]);
+        let plan = plan_rollback(1, applied, &existing, &provenance, &no_safelist());
+        assert_eq!(plan.changes, [change(Action::Remove, "3.3.3.3")]);
+        assert_eq!(plan.skipped, [change(Action::Add, "1.1.1.1")]);
+    }
+
+    #[test]
+    fn entries_no_longer_in_the_access_rule_are_left_alone() {
+        let applied = vec![
+            change(Action::Add, "1.1.1.1"),
+            change(Action::Remove, "2.2.2.2"),
+        ];
+        let provenance = BTreeMap::from([("1.1.1.1".to_string(), added_by(1))]);
+        // the added entry was removed by hand, the removed one is back already
+        let existing = strings(&["2.2.2.2"]);
+        let plan = plan_rollback(1, applied.clone(), &existing, &provenance, &no_safelist());
+        assert!(plan.changes.is_empty());
+        assert_eq!(plan.skipped, applied.into_iter().rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn removed_entries_now_safelisted_are_not_added_back() {
+        let applied = vec![
+            change(Action::Remove, "1.1.1.1"),
+            change(Action::Remove, "10.0.0.0/8"),
+            change(Action::Remove, "2.2.2.0/24"),
+            change(Action::Add, "3.3.3.3"),
+        ];
+        let provenance = BTreeMap::from([("3.3.3.3".to_string(), added_by(1))]);
+        let existing = strings(&["3.3.3.3"]);
+        let safelist = Safelist::new(&SafelistSettings {
+            entries: strings(&["1.1.1.1", "2.2.2.2"]),
+            reserved_ranges: true,
+        });
+        let plan = plan_rollback(1, applied, &existing, &provenance, &safelist);
+        assert_eq!(plan.changes, [change(Action::Remove, "3.3.3.3")]);
+        assert!(plan.skipped.is_empty());
+        let held: Vec<_> = plan
+            .conflicts
+            .iter()
+            .map(|c| (c.entry.as_str(), c.safelisted.as_str(), c.reason.as_str()))
+            .collect();
+        assert_eq!(
+            held,
+            [
+                ("2.2.2.0/24", "2.2.2.2", "safelist"),
+                ("10.0.0.0/8", "10.0.0.0/8", "private network"),
+                ("1.1.1.1", "1.1.1.1", "safelist"),
+            ]
+        );
+    }
+}
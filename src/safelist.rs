@@ -0,0 +1,190 @@
+use ipnet::IpNet;
+
+use crate::settings::SafelistSettings;
+use crate::validate::{Validated, canonical, parse_entry, parse_networks};
+
+/// Ranges that are never routable on the public internet, protected when
+/// [`SafelistSettings::reserved_ranges`] is set.
+pub const RESERVED_RANGES: &[(&str, &str)] = &[
+    ("0.0.0.0/8", "\"this\" network"),
+    ("10.0.0.0/8", "private network"),
+    ("100.64.0.0/10", "shared address space"),
+    ("127.0.0.0/8", "loopback"),
+    ("169.254.0.0/16", "link-local"),
+    ("172.16.0.0/12", "private network"),
+    ("192.0.0.0/24", "IETF protocol assignments"),
+    ("192.0.2.0/24", "documentation"),
+    ("192.168.0.0/16", "private network"),
+    ("198.18.0.0/15", "benchmarking"),
+    ("198.51.100.0/24", "documentation"),
+    ("203.0.113.0/24", "documentation"),
+    ("224.0.0.0/4", "multicast"),
+    ("240.0.0.0/4", "reserved"),
+    ("::/128", "unspecified address"),
+    ("::1/128", "loopback"),
+    ("100::/64", "discard-only"),
+    ("2001:db8::/32", "documentation"),
+    ("fc00::/7", "unique local"),
+    ("fe80::/10", "link-local"),
+    ("ff00::/8", "multicast"),
+];
+
+/// An entry of a list that was not imported because it overlaps a protected network.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct Conflict {
+    pub entry: String,
+    /// The protected network the entry overlaps.
+    pub safelisted: String,
+    /// Why the network is protected: `safelist` for configured entries, or the kind of
+    /// reserved range.
+    pub reason: String,
+}
+
+/// The networks imports must not block.
+#[derive(Clone, Debug, Default)]
+pub struct Safelist {
+    networks: Vec<(IpNet, &'static str)>,
+}
+
+impl Safelist {
+    pub fn new(settings: &SafelistSettings) -> Self {
+        let configured = settings
+            .entries
+            .iter()
+            // the settings were validated when they were saved
+            .filter_map(|entry| parse_networks(entry.trim()).ok())
+            .flatten()
+            .map(|net| (net, "safelist"));
+        let reserved = RESERVED_RANGES
+            .iter()
+            .filter(|_| settings.reserved_ranges)
+            .map(|(net, reason)| (parse_entry(net).expect("reserved range is valid"), *reason));
+        Self {
+            networks: configured.chain(reserved).collect(),
+        }
+    }
+
+    /// The protected network `net` overlaps, if any. A network that contains a protected
+    /// address overlaps it as much as one contained in it.
+    fn overlap(&self, net: &IpNet) -> Option<&(IpNet, &'static str)> {
+        self.networks
+            .iter()
+            .find(|(safe, _)| safe.contains(net) || net.contains(safe))
+    }
+
+    /// Move the entries of `validated` that overlap a protected network to its conflicts.
+    pub fn screen(&self, validated: &mut Validated) {
+        let mut conflicts = Vec::new();
+        validated.valid.retain(|net| match self.overlap(net) {
+            Some((safe, reason)) => {
+                validated.references.remove(net);
+                conflicts.push(Conflict {
+                    entry: canonical(net),
+                    safelisted: canonical(safe),
+                    reason: reason.to_string(),
+                });
+                false
+            }
+            None => true,
+        });
+        validated.conflicts.extend(conflicts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn safelist(entries: &[&str], reserved_ranges: bool) -> Safelist {
+        Safelist::new(&SafelistSettings {
+            entries: entries.iter().map(|entry| entry.to_string()).collect(),
+            reserved_ranges,
+        })
+    }
+
+    /// Screen `entries`, returning what is kept and what is held back.
+    fn screen(safelist: &Safelist, entries: &[&str]) -> (Vec<String>, Vec<String>) {
+        let mut validated = Validated {
+            valid: entries.iter().map(|e| parse_entry(e).unwrap()).collect(),
+            ..Validated::default()
+        };
+        safelist.screen(&mut validated);
+        let kept = validated.valid.iter().map(canonical).collect();
+        let held = validated.conflicts.into_iter().map(|c| c.entry).collect();
+        (kept, held)
+    }
+
+    #[test]
+    fn configured_addresses_and_networks_are_held_back() {
+        let safelist = safelist(&["1.1.1.1", "8.8.8.0/24", "9.9.9.0-9.9.9.3"], false);
+        let (kept, held) = screen(
+            &safelist,
+            &["1.1.1.1", "1.1.1.2", "8.8.8.8", "8.8.9.0/24", "9.9.9.2"],
+        );
+        assert_eq!(kept, ["1.1.1.2", "8.8.9.0/24"]);
+        assert_eq!(held, ["1.1.1.1", "8.8.8.8", "9.9.9.2"]);
+    }
+
+    #[test]
+    fn a_network_containing_a_safelisted_address_is_held_back() {
+        let safelist = safelist(&["1.1.1.1"], false);
+        let (kept, held) = screen(&safelist, &["1.1.0.0/16", "0.0.0.0/0", "1.2.0.0/16"]);
+        assert_eq!(kept, ["1.2.0.0/16"]);
+        assert_eq!(held, ["1.1.0.0/16", "0.0.0.0/0"]);
+    }
+
+    #[test]
+    fn reserved_ranges_are_held_back_only_when_enabled() {
+        let entries = ["10.1.2.3", "192.168.0.0/24", "127.0.0.1", "8.8.8.8"];
+        let (kept, held) = screen(&safelist(&[], true), &entries);
+        assert_eq!(kept, ["8.8.8.8"]);
+        assert_eq!(held, ["10.1.2.3", "192.168.0.0/24", "127.0.0.1"]);
+
+        let (kept, held) = screen(&safelist(&[], false), &entries);
+        assert_eq!(kept, entries);
+        assert!(held.is_empty());
+    }
+
+    #[test]
+    fn ipv6_entries_are_screened() {
+        let safelist = safelist(&["2606:4700::/32"], true);
+        let (kept, held) = screen(
+            &safelist,
+            &["2606:4700::1111", "2606::/16", "::1", "fe80::1", "2a00::1"],
+        );
+        assert_eq!(kept, ["2a00::1"]);
+        assert_eq!(held, ["2606:4700::1111", "2606::/16", "::1", "fe80::1"]);
+    }
+
+    #[test]
+    fn the_conflict_names_the_protected_network_and_why() {
+        let safelist = safelist(&["1.1.1.1/32"], true);
+        let mut validated = Validated {
+            valid: vec![
+                parse_entry("1.1.0.0/16").unwrap(),
+                parse_entry("10.0.0.1").unwrap(),
+            ],
+            ..Validated::default()
+        };
+        validated
+            .references
+            .insert(parse_entry("1.1.0.0/16").unwrap(), "SBL1".to_string());
+        safelist.screen(&mut validated);
+
+        assert!(validated.valid.is_empty());
+        // the reference of an entry that is held back is dropped with it
+        assert!(validated.references.is_empty());
+        let conflicts: Vec<(&str, &str, &str)> = validated
+            .conflicts
+            .iter()
+            .map(|c| (c.entry.as_str(), c.safelisted.as_str(), c.reason.as_str()))
+            .collect();
+        assert_eq!(
+            conflicts,
+            [
+                ("1.1.0.0/16", "1.1.1.1", "safelist"),
+                ("10.0.0.1", "10.0.0.0/8", "private network"),
+            ]
+        );
+    }
+}
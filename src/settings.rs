@@ -0,0 +1,211 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use axum::{Json, debug_handler};
+use tokio::sync::RwLock;
+
+use crate::AppState;
+use crate::errors::Error;
+use crate::validate::parse_networks;
+
+/// Runtime-configurable behaviour of the import engine.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub throughput: ThroughputSettings,
+    pub retry: RetrySettings,
+    pub circuit_breaker: CircuitBreakerSettings,
+    pub safelist: SafelistSettings,
+}
+
+/// Slowest rate limit allowed, one request every 100 seconds.
+pub const MIN_REQUESTS_PER_SECOND: f64 = 0.01;
+
+/// How hard imports may push the Zoraxy API.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ThroughputSettings {
+    /// Number of requests an import keeps in flight at once.
+    pub workers: usize,
+    /// Number of jobs that may run at once, each for a different Access Rule.
+    pub concurrent_jobs: usize,
+    /// Requests per second sent to Zoraxy across all imports, `0` for no limit, otherwise at least
+    /// [`MIN_REQUESTS_PER_SECOND`].
+    pub requests_per_second: f64,
+    /// Requests that may be sent back to back before the rate limit kicks in.
+    pub burst: u32,
+}
+
+impl Default for ThroughputSettings {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            concurrent_jobs: 1,
+            requests_per_second: 50.0,
+            burst: 20,
+        }
+    }
+}
+
+/// How often, and how patiently, a single Zoraxy API call is retried after a transient failure.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RetrySettings {
+    /// Total number of attempts per request, including the first one.
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Factor the backoff grows by after each failed attempt.
+    pub multiplier: f64,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            initial_backoff_ms: 250,
+            max_backoff_ms: 10_000,
+            multiplier: 2.0,
+        }
+    }
+}
+
+/// When to consider Zoraxy down and pause imports instead of failing every remaining IP.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CircuitBreakerSettings {
+    /// Changes in a row that still fail transiently after their retries before the circuit opens.
+    pub failure_threshold: u32,
+    /// How long imports pause before probing Zoraxy again.
+    pub cooldown_secs: u64,
+    /// How long an import may stay paused before it is failed.
+    pub max_pause_secs: u64,
+}
+
+impl Default for CircuitBreakerSettings {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown_secs: 30,
+            max_pause_secs: 30 * 60,
+        }
+    }
+}
+
+/// Addresses imports must never block, so a bad list can't lock anyone out of their own proxy.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SafelistSettings {
+    /// IPs, CIDR networks or ranges that are never added to an Access Rule.
+    pub entries: Vec<String>,
+    /// Also protect private, loopback, link-local and other reserved ranges, see
+    /// [`crate::safelist::RESERVED_RANGES`].
+    pub reserved_ranges: bool,
+}
+
+impl Default for SafelistSettings {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+            reserved_ranges: true,
+        }
+    }
+}
+
+impl Settings {
+    /// Check that the settings describe a usable configuration.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=64).contains(&self.throughput.workers) {
+            return Err("throughput.workers must be between 1 and 64".to_string());
+        }
+        if !(1..=16).contains(&self.throughput.concurrent_jobs) {
+            return Err("throughput.concurrent_jobs must be between 1 and 16".to_string());
+        }
+        let rate = self.throughput.requests_per_second;
+        if !(rate == 0.0 || (MIN_REQUESTS_PER_SECOND..=f64::MAX).contains(&rate)) {
+            return Err(format!(
+                "throughput.requests_per_second must be 0 or a finite number of at least \
+                 {MIN_REQUESTS_PER_SECOND}"
+            ));
+        }
+        if self.throughput.burst == 0 {
+            return Err("throughput.burst must be at least 1".to_string());
+        }
+        if self.retry.max_attempts == 0 {
+            return Err("retry.max_attempts must be at least 1".to_string());
+        }
+        if !(self.retry.multiplier >= 1.0 && self.retry.multiplier.is_finite()) {
+            return Err("retry.multiplier must be a finite number of at least 1".to_string());
+        }
+        if self.retry.initial_backoff_ms > self.retry.max_backoff_ms {
+            return Err(
+                "retry.initial_backoff_ms must not exceed retry.max_backoff_ms".to_string(),
+            );
+        }
+        if self.circuit_breaker.failure_threshold == 0 {
+            return Err("circuit_breaker.failure_threshold must be at least 1".to_string());
+        }
+        if self.circuit_breaker.cooldown_secs == 0 {
+            return Err("circuit_breaker.cooldown_secs must be at least 1".to_string());
+        }
+        for entry in &self.safelist.entries {
+            parse_networks(entry.trim())
+                .map_err(|e| format!("safelist.entries: `{entry}` is not valid: {e}"))?;
+        }
+        Ok(())
+    }
+}
+
+pub type SharedSettings = Arc<RwLock<Settings>>;
+
+#[debug_handler]
+pub async fn handle_get_settings(State(state): State<AppState>) -> Json<Settings> {
+    Json(state.settings.read().await.clone())
+}
+
+#[debug_handler]
+pub async fn handle_put_settings(
+    State(state): State<AppState>,
+    Json(settings): Json<Settings>,
+) -> Result<Json<Settings>, Error> {
+    settings.validate().map_err(Error::InvalidSettings)?;
+    state
+        .rate_limiter
+        .reconfigure(
+            settings.throughput.requests_per_second,
+            settings.throughput.burst,
+        )
+        .await;
+    *state.settings.write().await = settings.clone();
+    state.store.changes.notify();
+    // more jobs may be allowed to run now
+    state.queue.wake();
+    tracing::info!(?settings, "Settings updated");
+    Ok(Json(settings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_rate(requests_per_second: f64) -> Settings {
+        let mut settings = Settings::default();
+        settings.throughput.requests_per_second = requests_per_second;
+        settings
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(Settings::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn rate_is_unlimited_or_at_least_the_minimum() {
+        assert!(with_rate(0.0).validate().is_ok());
+        assert!(with_rate(MIN_REQUESTS_PER_SECOND).validate().is_ok());
+        assert!(with_rate(1e-20).validate().is_err());
+        assert!(with_rate(-1.0).validate().is_err());
+        assert!(with_rate(f64::INFINITY).validate().is_err());
+        assert!(with_rate(f64::NAN).validate().is_err());
+    }
+}
@@ -0,0 +1,142 @@
+//! Spamhaus DROP, EDROP and DROPv6 lists, in the classic text layout and the newer JSON lines.
+
+use crate::parser::RawEntry;
+
+/// How many entries are looked at to recognize a list.
+const DETECT_LINES: usize = 20;
+
+/// An entry of the JSON lines layout, which ends with a `{"type": "metadata", ...}` line.
+#[derive(Debug, serde::Deserialize)]
+struct Record {
+    cidr: Option<String>,
+    sblid: Option<String>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+/// Whether `input` looks like a DROP list: `CIDR ; SBLxxxx` lines, or JSON lines with a `cidr`
+/// and an `sblid`.
+pub fn detect(input: &str) -> bool {
+    let mut lines = content_lines(input).take(DETECT_LINES).peekable();
+    if lines.peek().is_none() {
+        return false;
+    }
+    lines.all(|(_, line)| match line.strip_prefix('{') {
+        Some(_) => serde_json::from_str::<Record>(line)
+            .is_ok_and(|record| record.sblid.is_some() || record.kind.is_some()),
+        None => line
+            .split_once(';')
+            .is_some_and(|(_, reference)| reference.trim().starts_with("SBL")),
+    })
+}
+
+/// Split a DROP list into its entries, each with its SBL reference.
+///
+/// Lines that are not understood are kept whole, so they are reported as invalid entries rather
+/// than silently dropped.
+pub fn parse_drop(input: &str) -> Vec<RawEntry> {
+    content_lines(input)
+        .filter_map(|(line, text)| {
+            let (text, reference) = if text.starts_with('{') {
+                match serde_json::from_str::<Record>(text) {
+                    // the trailing metadata record describes the list itself
+                    Ok(Record {
+                        kind: Some(kind), ..
+                    }) if kind == "metadata" => return None,
+                    Ok(Record {
+                        cidr: Some(cidr),
+                        sblid,
+                        ..
+                    }) => (cidr, sblid),
+                    _ => (text.to_string(), None),
+                }
+            } else {
+                match text.split_once(';') {
+                    Some((cidr, reference)) => {
+                        let reference = reference.trim();
+                        (
+                            cidr.trim().to_string(),
+                            (!reference.is_empty()).then(|| reference.to_string()),
+                        )
+                    }
+                    None => (text.to_string(), None),
+                }
+            };
+            Some(RawEntry {
+                line,
+                text,
+                reference,
+            })
+        })
+        .collect()
+}
+
+/// The non-empty lines of `input` that are not `;` comments, with their 1-based line number.
+fn content_lines(input: &str) -> impl Iterator<Item = (usize, &str)> {
+    let input = input.strip_prefix('\u{feff}').unwrap_or(input);
+    input
+        .split('\n')
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with(';'))
+        .map(|(i, line)| (i + 1, line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DROP: &str = "; Spamhaus DROP List 2024/01/01 - (c) 2024 The Spamhaus Project\n\
+                        ; Last-Modified: Mon, 01 Jan 2024 00:00:00 GMT\n\
+                        \n\
+                        1.10.16.0/20 ; SBL256894\n\
+                        1.19.0.0/16 ; SBL434604\n";
+
+    const DROP_JSON: &str = r#"{"cidr":"1.10.16.0/20","sblid":"SBL256894","rir":"apnic"}
+{"cidr":"2001:db8::/32","sblid":"SBL1","rir":"ripencc"}
+{"type":"metadata","timestamp":1704067200,"size":2,"records":2,"copyright":"(c) 2024"}
+"#;
+
+    #[test]
+    fn detects_both_layouts() {
+        assert!(detect(DROP));
+        assert!(detect(DROP_JSON));
+        assert!(!detect("1.2.3.4\n5.6.7.8\n"));
+        assert!(!detect("; only comments\n"));
+        assert!(!detect(r#"{"ips": ["1.2.3.4"]}"#));
+    }
+
+    #[test]
+    fn keeps_sbl_references() {
+        let entries = parse_drop(DROP);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].line, 4);
+        assert_eq!(entries[0].text, "1.10.16.0/20");
+        assert_eq!(entries[0].reference.as_deref(), Some("SBL256894"));
+    }
+
+    #[test]
+    fn reads_json_lines_and_skips_the_metadata() {
+        let entries = parse_drop(DROP_JSON);
+        let texts: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.text.as_str(), entry.reference.as_deref()))
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                ("1.10.16.0/20", Some("SBL256894")),
+                ("2001:db8::/32", Some("SBL1"))
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_lines_it_does_not_understand_whole() {
+        let entries = parse_drop("1.2.3.0/24 ;\n{\"oops\": 1}\ngarbage here\n");
+        assert_eq!(entries[0].text, "1.2.3.0/24");
+        assert_eq!(entries[0].reference, None);
+        assert_eq!(entries[1].text, "{\"oops\": 1}");
+        assert_eq!(entries[2].text, "garbage here");
+    }
+}
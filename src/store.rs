@@ -0,0 +1,360 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::sync::Notify;
+
+use crate::jobs::unix_now;
+use crate::settings::Settings;
+use crate::{AppState, feeds, jobs};
+
+/// Name of the state file, kept in the plugin's working directory.
+pub const STATE_FILE: &str = "blocklist-import-state.json";
+/// Name of the directory next to the state file holding the files of each job.
+pub const JOBS_DIR: &str = "blocklist-import-jobs";
+/// Name of the directory next to the state file that `file://` feeds may read lists from.
+pub const LISTS_DIR: &str = "blocklist-import-lists";
+/// Name of the provenance log, kept next to the state file.
+pub const PROVENANCE_FILE: &str = "blocklist-import-provenance.jsonl";
+/// Version of the layout of the state file, bumped by adding a migration to [`MIGRATIONS`].
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32 + 1;
+/// Upgrades a state file in place from one schema version to the next.
+type Migration = fn(&mut Value) -> Result<(), String>;
+/// Upgrades from each older schema version to the next, `MIGRATIONS[0]` upgrades version 1 to 2.
+const MIGRATIONS: &[Migration] = &[];
+/// How long changes are collected before the state is written out, so a running import
+/// doesn't rewrite the file for every IP.
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// Everything the plugin keeps across restarts.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct PersistedState {
+    pub schema_version: u32,
+    pub settings: Settings,
+    pub feeds: feeds::Registry,
+    pub jobs: jobs::Registry,
+}
+
+/// Signals the store that some of the state it persists has changed.
+#[derive(Clone, Debug, Default)]
+pub struct Changes(Arc<Notify>);
+
+impl Changes {
+    pub fn notify(&self) {
+        // a stored permit is enough, changes made while a save is pending are picked up by it
+        self.0.notify_one();
+    }
+}
+
+/// The JSON file the plugin's state is saved to.
+#[derive(Clone, Debug)]
+pub struct Store {
+    path: Arc<PathBuf>,
+    pub changes: Changes,
+    /// Set when a state file that could not be loaded could not be moved aside either, so it is
+    /// never overwritten.
+    read_only: Arc<AtomicBool>,
+}
+
+impl Store {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path: Arc::new(path),
+            changes: Changes::default(),
+            read_only: Arc::default(),
+        }
+    }
+
+    /// Read the saved state, migrating it from older schema versions.
+    ///
+    /// A missing file is a fresh install. A file that can't be read is moved aside, so it is
+    /// not overwritten, and the plugin starts from scratch.
+    pub fn load(&self) -> PersistedState {
+        let path = self.path.as_path();
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::info!("No saved state at {}, starting fresh", path.display());
+                return PersistedState::default();
+            }
+            Err(e) => {
+                self.set_aside(
+                    &format!("Could not read saved state from {}", path.display()),
+                    e,
+                );
+                return PersistedState::default();
+            }
+        };
+
+        match decode(&data) {
+            Ok(state) => {
+                tracing::info!(
+                    feeds = state.feeds.feeds.len(),
+                    jobs = state.jobs.jobs.len(),
+                    "Loaded saved state from {}",
+                    path.display()
+                );
+                state
+            }
+            Err(e) => {
+                self.set_aside(
+                    &format!("Could not load saved state from {}", path.display()),
+                    e,
+                );
+                PersistedState::default()
+            }
+        }
+    }
+
+    /// Move a state file that could not be loaded out of the way, or stop saving over it if it
+    /// can't be moved.
+    fn set_aside(&self, message: &str, error: impl std::fmt::Display) {
+        let path = self.path.as_path();
+        let aside = path.with_extension(format!("json.unreadable-{}", unix_now()));
+        tracing::error!("{message}, moving it to {}: {error}", aside.display());
+        if let Err(e) = std::fs::rename(path, &aside) {
+            tracing::error!(
+                "Could not move {} aside, changes will not be saved: {e}",
+                path.display()
+            );
+            self.read_only.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Write `state` to the state file, replacing it atomically.
+    async fn save(&self, state: &PersistedState) -> std::io::Result<()> {
+        if self.read_only.load(Ordering::Relaxed) {
+            return Err(std::io::Error::other(
+                "the file could not be loaded and is kept as it is",
+            ));
+        }
+        let data = serde_json::to_vec_pretty(state)?;
+        let tmp = self.path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, self.path.as_path()).await
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Parse a state file, upgrading it to the current schema version.
+fn decode(data: &[u8]) -> Result<PersistedState, String> {
+    let mut value: Value = serde_json::from_slice(data).map_err(|e| e.to_string())?;
+    upgrade(&mut value, MIGRATIONS)?;
+    serde_json::from_value(value).map_err(|e| e.to_string())
+}
+
+/// Run the `migrations` a state file has not had yet, leaving it at the version they lead up to.
+fn upgrade(value: &mut Value, migrations: &[Migration]) -> Result<(), String> {
+    let latest = migrations.len() as u64 + 1;
+    let version = value
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .ok_or("missing `schema_version`")?;
+    if version == 0 || version > latest {
+        return Err(format!(
+            "schema version {version} is not supported, expected at most {latest}"
+        ));
+    }
+
+    for (from, migrate) in migrations.iter().enumerate().skip(version as usize - 1) {
+        migrate(value).map_err(|e| format!("migrating from version {}: {e}", from + 1))?;
+        tracing::info!("Migrated saved state to schema version {}", from + 2);
+    }
+    value["schema_version"] = latest.into();
+    Ok(())
+}
+
+/// Take a snapshot of the plugin's state.
+async fn snapshot(ctx: &AppState) -> PersistedState {
+    PersistedState {
+        schema_version: SCHEMA_VERSION,
+        settings: ctx.settings.read().await.clone(),
+        feeds: ctx.feeds.snapshot().await,
+        jobs: ctx.jobs.snapshot().await,
+    }
+}
+
+/// Save the plugin's state whenever it changes, for as long as the plugin runs.
+pub async fn run_saver(ctx: AppState) {
+    // write the (possibly migrated) state back straight away
+    ctx.store.changes.notify();
+    loop {
+        ctx.store.changes.0.notified().await;
+        tokio::time::sleep(SAVE_DELAY).await;
+
+        let state = snapshot(&ctx).await;
+        match ctx.store.save(&state).await {
+            Ok(()) => tracing::debug!("Saved state to {}", ctx.store.path().display()),
+            Err(e) => tracing::error!(
+                "Could not save state to {}: {e}",
+                ctx.store.path().display()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::feeds::NewFeed;
+    use crate::plan::{Action, Change, ImportMode};
+    use crate::provenance::ProvenanceRegistry;
+    use crate::queue::Priority;
+    use crate::validate::{Validated, parse_entry};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("store-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn a_state_file_that_cant_be_read_is_moved_aside() {
+        let dir = temp_dir("unreadable");
+        // reading a directory fails with an I/O error other than "not found"
+        let path = dir.join(STATE_FILE);
+        std::fs::create_dir(&path).unwrap();
+
+        let store = Store::new(path.clone());
+        store.load();
+        assert!(!path.exists());
+        assert!(!store.read_only.load(Ordering::Relaxed));
+        let aside = std::fs::read_dir(&dir).unwrap().count();
+        assert_eq!(aside, 1);
+    }
+
+    #[test]
+    fn unsupported_schema_versions_are_refused() {
+        for data in [
+            json!({ "schema_version": SCHEMA_VERSION + 1 }),
+            json!({ "schema_version": 0 }),
+            json!({ "schema_version": "1" }),
+            json!({ "settings": {} }),
+        ] {
+            let data = serde_json::to_vec(&data).unwrap();
+            assert!(decode(&data).is_err(), "{}", String::from_utf8_lossy(&data));
+        }
+        assert!(decode(b"{\"schema_version\": 1").is_err());
+
+        let data = json!({ "schema_version": SCHEMA_VERSION });
+        let state = decode(&serde_json::to_vec(&data).unwrap()).unwrap();
+        assert_eq!(state.schema_version, SCHEMA_VERSION);
+    }
+
+    fn rename_interval(value: &mut Value) -> Result<(), String> {
+        let interval = value["interval"].take();
+        value["interval_secs"] = interval;
+        Ok(())
+    }
+
+    fn require_name(value: &mut Value) -> Result<(), String> {
+        match value.get("name") {
+            Some(_) => Ok(()),
+            None => Err("missing `name`".to_string()),
+        }
+    }
+
+    fn add_enabled(value: &mut Value) -> Result<(), String> {
+        value["enabled"] = true.into();
+        Ok(())
+    }
+
+    #[test]
+    fn older_state_is_migrated_one_version_at_a_time() {
+        let migrations: &[Migration] = &[rename_interval, add_enabled];
+        let mut value = json!({ "schema_version": 1, "interval": 60 });
+        upgrade(&mut value, migrations).unwrap();
+        assert_eq!(
+            value,
+            json!({ "schema_version": 3, "interval": null, "interval_secs": 60, "enabled": true })
+        );
+
+        // only the migrations past the file's version are run
+        let mut value = json!({ "schema_version": 2, "interval": 60 });
+        upgrade(&mut value, migrations).unwrap();
+        assert_eq!(
+            value,
+            json!({ "schema_version": 3, "interval": 60, "enabled": true })
+        );
+
+        let mut value = json!({ "schema_version": 3 });
+        assert!(upgrade(&mut value, migrations).is_ok());
+        let mut value = json!({ "schema_version": 4 });
+        assert!(upgrade(&mut value, migrations).is_err());
+    }
+
+    #[test]
+    fn a_migration_that_fails_fails_the_upgrade() {
+        let migrations: &[Migration] = &[add_enabled, require_name];
+        let mut value = json!({ "schema_version": 1 });
+        let error = upgrade(&mut value, migrations).unwrap_err();
+        assert_eq!(error, "migrating from version 2: missing `name`");
+    }
+
+    #[tokio::test]
+    async fn saved_state_loads_back_the_same() {
+        let ctx = AppState::for_tests("store-round-trip");
+        ctx.settings.write().await.throughput.workers = 7;
+        ctx.settings.write().await.safelist.entries = vec!["192.0.2.1".to_string()];
+        let new_feed: NewFeed = serde_json::from_value(json!({
+            "url": "https://example.com/list.txt",
+            "access_rule_id": "rule",
+            "interval_secs": 3600,
+            "format": "csv",
+            "columns": { "ip": 0 },
+        }))
+        .unwrap();
+        ctx.feeds.create(new_feed).await;
+        let validated = Validated {
+            valid: vec![parse_entry("1.1.1.1").unwrap()],
+            ..Validated::default()
+        };
+        let job = ctx
+            .jobs
+            .create(
+                "rule".to_string(),
+                ImportMode::Sync,
+                &validated,
+                Some(1),
+                Priority::Low,
+            )
+            .await;
+
+        let saved = snapshot(&ctx).await;
+        ctx.store.save(&saved).await.unwrap();
+        let loaded = Store::new(ctx.store.path().to_path_buf()).load();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&saved).unwrap()
+        );
+        assert_eq!(loaded.settings.throughput.workers, 7);
+        assert_eq!(loaded.feeds.feeds.len(), 1);
+        assert_eq!(loaded.jobs.jobs[&job.id].access_rule_id, "rule");
+
+        let path = ctx.store.path().with_file_name(PROVENANCE_FILE);
+        let provenance = ProvenanceRegistry::load(path.clone());
+        let change = Change {
+            action: Action::Add,
+            entry: "1.1.1.1".to_string(),
+        };
+        provenance
+            .record("rule", &change, job.id, Some(1), Some("SBL1".to_string()))
+            .await;
+        provenance.flush().await;
+        let loaded = ProvenanceRegistry::load(path).for_rule("rule").await;
+        let added = &loaded["1.1.1.1"];
+        assert_eq!(
+            (added.job_id, added.feed_id, added.reference.as_deref()),
+            (job.id, Some(1), Some("SBL1"))
+        );
+    }
+}
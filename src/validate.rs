@@ -0,0 +1,307 @@
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnet::{IpNet, Ipv4Subnets, Ipv6Subnets};
+
+use crate::parser::{ListMetadata, RawEntry};
+use crate::safelist::Conflict;
+
+/// An entry of a blocklist that could not be understood as an IP, CIDR or range.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct InvalidEntry {
+    pub line: usize,
+    pub entry: String,
+    pub reason: String,
+}
+
+/// Result of validating every entry of a blocklist.
+#[derive(Clone, Debug, Default)]
+pub struct Validated {
+    pub valid: Vec<IpNet>,
+    pub invalid: Vec<InvalidEntry>,
+    /// Valid entries that were dropped because they overlap the safelist, see
+    /// [`crate::safelist::Safelist::screen`].
+    pub conflicts: Vec<Conflict>,
+    /// Rows of the list left out by the filters of the request.
+    pub filtered: usize,
+    /// The list's own reference for valid entries that came with one.
+    pub references: BTreeMap<IpNet, String>,
+    /// What the list says about itself, if it says anything.
+    pub metadata: Option<Box<ListMetadata>>,
+}
+
+/// Validate and normalize each of `entries`, splitting them into valid and invalid ones.
+pub fn validate_entries(entries: Vec<RawEntry>) -> Validated {
+    let mut validated = Validated::default();
+    for entry in entries {
+        match parse_networks(&entry.text) {
+            Ok(nets) => {
+                if let Some(reference) = &entry.reference {
+                    for net in &nets {
+                        validated.references.insert(*net, reference.clone());
+                    }
+                }
+                validated.valid.extend(nets);
+            }
+            Err(reason) => validated.invalid.push(InvalidEntry {
+                line: entry.line,
+                entry: entry.text,
+                reason,
+            }),
+        }
+    }
+    validated
+}
+
+/// The canonical form of an entry as sent to Zoraxy: a bare address for single hosts,
+/// `network/prefix` otherwise.
+pub fn canonical(net: &IpNet) -> String {
+    if net.prefix_len() == net.max_prefix_len() {
+        net.addr().to_string()
+    } else {
+        net.to_string()
+    }
+}
+
+/// Parse an entry of a blocklist: an IP address, a CIDR network, or a range of addresses like
+/// `1.2.3.0-1.2.3.255`, which is split into the fewest networks that cover it exactly.
+pub fn parse_networks(text: &str) -> Result<Vec<IpNet>, String> {
+    match text.split_once('-') {
+        Some((start, end)) => parse_range(start.trim(), end.trim()),
+        None => parse_entry(text).map(|net| vec![net]),
+    }
+}
+
+fn parse_range(start: &str, end: &str) -> Result<Vec<IpNet>, String> {
+    let start = unmap(parse_addr(start)?);
+    let end = unmap(parse_addr(end)?);
+    if start > end {
+        return Err("the range ends before it starts".to_string());
+    }
+    match (start, end) {
+        (IpAddr::V4(start), IpAddr::V4(end)) => {
+            Ok(Ipv4Subnets::new(start, end, 0).map(IpNet::V4).collect())
+        }
+        (IpAddr::V6(start), IpAddr::V6(end)) => {
+            Ok(Ipv6Subnets::new(start, end, 0).map(IpNet::V6).collect())
+        }
+        _ => Err("the range mixes IPv4 and IPv6 addresses".to_string()),
+    }
+}
+
+/// Unwrap an IPv4-mapped IPv6 address to plain IPv4.
+fn unmap(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Parse a single IP address or CIDR network strictly.
+///
+/// IPv4 octets with leading zeros are rejected, as some software reads them as octal. IPv4-mapped
+/// IPv6 addresses are unwrapped to plain IPv4, and networks must not have host bits set.
+pub fn parse_entry(text: &str) -> Result<IpNet, String> {
+    let (addr, prefix) = match text.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (text, None),
+    };
+
+    let addr = parse_addr(addr)?;
+    let max_prefix = match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    let prefix = match prefix {
+        Some(prefix) => parse_prefix(prefix, max_prefix)?,
+        None => max_prefix,
+    };
+
+    let net = match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) if prefix >= 96 => IpNet::new(IpAddr::V4(v4), prefix - 96),
+            Some(_) => return Err("IPv4-mapped network prefix must be at least 96".to_string()),
+            None => IpNet::new(addr, prefix),
+        },
+        IpAddr::V4(_) => IpNet::new(addr, prefix),
+    }
+    .map_err(|e| e.to_string())?;
+
+    if net.trunc() != net {
+        return Err(format!(
+            "host bits are set, the network is {}",
+            canonical(&net.trunc())
+        ));
+    }
+
+    Ok(net)
+}
+
+fn parse_addr(addr: &str) -> Result<IpAddr, String> {
+    if addr.contains(':') {
+        if addr.contains('%') {
+            return Err("IPv6 zone identifiers are not allowed".to_string());
+        }
+        return addr
+            .parse::<Ipv6Addr>()
+            .map(IpAddr::V6)
+            .map_err(|_| "not a valid IPv6 address".to_string());
+    }
+
+    let octets: Vec<&str> = addr.split('.').collect();
+    if octets.len() == 4
+        && octets
+            .iter()
+            .any(|octet| octet.len() > 1 && octet.starts_with('0'))
+    {
+        return Err("IPv4 octets must not have leading zeros".to_string());
+    }
+    addr.parse::<Ipv4Addr>()
+        .map(IpAddr::V4)
+        .map_err(|_| "not a valid IP address".to_string())
+}
+
+fn parse_prefix(prefix: &str, max_prefix: u8) -> Result<u8, String> {
+    let valid_digits = !prefix.is_empty()
+        && prefix.bytes().all(|b| b.is_ascii_digit())
+        && !(prefix.len() > 1 && prefix.starts_with('0'));
+    match prefix.parse::<u8>() {
+        Ok(prefix) if valid_digits && prefix <= max_prefix => Ok(prefix),
+        _ => Err(format!("prefix length must be between 0 and {max_prefix}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn canonical_networks(text: &str) -> Result<Vec<String>, String> {
+        parse_networks(text).map(|nets| nets.iter().map(canonical).collect())
+    }
+
+    #[test]
+    fn accepts_addresses_and_networks() {
+        assert_eq!(
+            canonical_networks("1.2.3.4"),
+            Ok(vec!["1.2.3.4".to_string()])
+        );
+        assert_eq!(
+            canonical_networks("1.2.3.4/32"),
+            Ok(vec!["1.2.3.4".to_string()])
+        );
+        assert_eq!(
+            canonical_networks("10.0.0.0/8"),
+            Ok(vec!["10.0.0.0/8".to_string()])
+        );
+        assert_eq!(
+            canonical_networks("2001:DB8::/32"),
+            Ok(vec!["2001:db8::/32".to_string()])
+        );
+        assert_eq!(
+            canonical_networks("0.0.0.0/0"),
+            Ok(vec!["0.0.0.0/0".to_string()])
+        );
+    }
+
+    #[test]
+    fn rejects_leading_zeros() {
+        assert!(parse_entry("01.2.3.4").is_err());
+        assert!(parse_entry("1.2.3.010").is_err());
+        assert!(parse_entry("1.2.3.0/024").is_err());
+        assert!(parse_entry("1.2.3.0").is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        for text in [
+            "",
+            "1.2.3",
+            "1.2.3.256",
+            "1.2.3.4/33",
+            "1.2.3.4/",
+            "1.2.3.4/-1",
+            "2001:db8::/129",
+            "2001:db8::g",
+            "fe80::1%eth0",
+            "example.com",
+        ] {
+            assert!(parse_entry(text).is_err(), "{text} should be rejected");
+        }
+    }
+
+    #[test]
+    fn rejects_host_bits() {
+        let error = parse_entry("1.2.3.4/24").unwrap_err();
+        assert!(error.contains("1.2.3.0/24"), "{error}");
+        assert!(parse_entry("2001:db8::1/64").is_err());
+    }
+
+    #[test]
+    fn unwraps_ipv4_mapped_addresses() {
+        assert_eq!(
+            canonical(&parse_entry("::ffff:1.2.3.4").unwrap()),
+            "1.2.3.4"
+        );
+        assert_eq!(
+            canonical(&parse_entry("::ffff:10.0.0.0/104").unwrap()),
+            "10.0.0.0/8"
+        );
+        assert!(parse_entry("::ffff:0.0.0.0/95").is_err());
+        assert_eq!(
+            canonical_networks("::ffff:1.2.3.0-1.2.3.255"),
+            Ok(vec!["1.2.3.0/24".to_string()])
+        );
+    }
+
+    #[test]
+    fn splits_ranges_into_the_fewest_networks() {
+        assert_eq!(
+            canonical_networks("1.2.3.0-1.2.3.255"),
+            Ok(vec!["1.2.3.0/24".to_string()])
+        );
+        assert_eq!(
+            canonical_networks("1.2.3.1-1.2.3.6"),
+            Ok(["1.2.3.1", "1.2.3.2/31", "1.2.3.4/31", "1.2.3.6"]
+                .map(String::from)
+                .to_vec())
+        );
+        assert_eq!(
+            canonical_networks("2001:db8::-2001:db8::ffff"),
+            Ok(vec!["2001:db8::/112".to_string()])
+        );
+        assert_eq!(
+            canonical_networks("1.2.3.4-1.2.3.4"),
+            Ok(vec!["1.2.3.4".to_string()])
+        );
+    }
+
+    #[test]
+    fn rejects_bad_ranges() {
+        assert!(parse_networks("1.2.3.9-1.2.3.1").is_err());
+        assert!(parse_networks("1.2.3.4-2001:db8::1").is_err());
+        assert!(parse_networks("1.2.3.0/24-1.2.4.0").is_err());
+        assert!(parse_networks("1.2.3.4-").is_err());
+    }
+
+    #[test]
+    fn splits_valid_from_invalid_entries() {
+        let entry = |line, text: &str, reference: Option<&str>| RawEntry {
+            line,
+            text: text.to_string(),
+            reference: reference.map(String::from),
+        };
+        let validated = validate_entries(vec![
+            entry(1, "1.2.3.0-1.2.3.1", Some("SBL1")),
+            entry(2, "bogus", None),
+            entry(3, "5.6.7.8", None),
+        ]);
+        assert_eq!(validated.valid.len(), 2);
+        assert_eq!(
+            validated.references.get(&"1.2.3.0/31".parse().unwrap()),
+            Some(&"SBL1".to_string())
+        );
+        assert_eq!(validated.invalid.len(), 1);
+        assert_eq!(validated.invalid[0].line, 2);
+        assert_eq!(validated.invalid[0].entry, "bogus");
+    }
+}
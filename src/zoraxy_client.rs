@@ -0,0 +1,270 @@
+use axum::body::Bytes;
+use reqwest::{Response, StatusCode};
+
+use crate::errors::Error;
+use crate::plan::{Action, Change};
+use crate::zoraxy_types::AccessRule;
+
+/// Why a call to the Zoraxy API did not succeed.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// Zoraxy understood the request but refused it (4xx, or an `error` in the response body).
+    Rejected,
+    /// Zoraxy did not accept the plugin's API key (401/403).
+    AuthFailure,
+    /// Zoraxy failed to handle the request (5xx).
+    ServerError,
+    /// The request never got a response (connection refused, timeout, ...).
+    Transport,
+}
+
+impl FailureKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FailureKind::Rejected => "rejected",
+            FailureKind::AuthFailure => "auth_failure",
+            FailureKind::ServerError => "server_error",
+            FailureKind::Transport => "transport",
+        }
+    }
+}
+
+/// A classified failure of a single Zoraxy API call.
+#[derive(Clone, Debug)]
+pub struct ApiFailure {
+    pub kind: FailureKind,
+    /// HTTP status code, if Zoraxy responded at all.
+    pub status: Option<u16>,
+    pub message: String,
+}
+
+impl ApiFailure {
+    /// Whether the same request might succeed if sent again later.
+    pub fn is_transient(&self) -> bool {
+        match self.kind {
+            FailureKind::Transport | FailureKind::ServerError => true,
+            // request timeout and rate limiting are reported as 4xx, but are worth retrying
+            FailureKind::Rejected => matches!(self.status, Some(408 | 429)),
+            FailureKind::AuthFailure => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ApiFailure {
+    fn from(e: reqwest::Error) -> Self {
+        Self {
+            kind: FailureKind::Transport,
+            status: e.status().map(|s| s.as_u16()),
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<ApiFailure> for Error {
+    fn from(failure: ApiFailure) -> Self {
+        Error::ZoraxyResponse {
+            kind: failure.kind,
+            message: failure.message,
+        }
+    }
+}
+
+/// Thin wrapper around the Zoraxy plugin API endpoints this plugin is permitted to call.
+#[derive(Clone, Debug)]
+pub struct ZoraxyClient {
+    client: reqwest::Client,
+    api_key: String,
+    port: u16,
+}
+
+impl ZoraxyClient {
+    pub fn new(client: reqwest::Client, api_key: String, port: u16) -> Self {
+        Self {
+            client,
+            api_key,
+            port,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://localhost:{}{path}", self.port)
+    }
+
+    /// Add a single IP to the blacklist of the given Access Rule.
+    pub async fn add_ip(&self, access_rule_id: &str, ip: &str) -> Result<(), ApiFailure> {
+        let response = self
+            .client
+            .post(self.url("/plugin/api/blacklist/ip/add"))
+            .query(&[("id", access_rule_id), ("ip", ip)])
+            .bearer_auth(&self.api_key)
+            .send()
+            .await?;
+
+        check_response(response).await.map(drop)
+    }
+
+    /// Remove a single IP from the blacklist of the given Access Rule.
+    pub async fn remove_ip(&self, access_rule_id: &str, ip: &str) -> Result<(), ApiFailure> {
+        let response = self
+            .client
+            .post(self.url("/plugin/api/blacklist/ip/remove"))
+            .query(&[("id", access_rule_id), ("ip", ip)])
+            .bearer_auth(&self.api_key)
+            .send()
+            .await?;
+
+        check_response(response).await.map(drop)
+    }
+
+    /// Apply a single planned change to the given Access Rule.
+    pub async fn apply(&self, access_rule_id: &str, change: &Change) -> Result<(), ApiFailure> {
+        match change.action {
+            Action::Add => self.add_ip(access_rule_id, &change.entry).await,
+            Action::Remove => self.remove_ip(access_rule_id, &change.entry).await,
+        }
+    }
+
+    pub async fn list_access_rules(&self) -> Result<Vec<AccessRule>, ApiFailure> {
+        let response = self
+            .client
+            .get(self.url("/plugin/api/access/list"))
+            .bearer_auth(&self.api_key)
+            .send()
+            .await?;
+
+        decode_json(&check_response(response).await?)
+    }
+
+    pub async fn list_blacklisted_ips(
+        &self,
+        access_rule_id: &str,
+    ) -> Result<Vec<String>, ApiFailure> {
+        let response = self
+            .client
+            .get(self.url("/plugin/api/blacklist/list"))
+            .query(&[("id", access_rule_id), ("type", "ip")])
+            .bearer_auth(&self.api_key)
+            .send()
+            .await?;
+
+        decode_json(&check_response(response).await?)
+    }
+}
+
+/// Classify a response from Zoraxy, returning its body if it indicates success.
+///
+/// Zoraxy reports some errors as `200 OK` with an `{"error": "..."}` body, so successful
+/// responses are also checked for an error body.
+async fn check_response(response: Response) -> Result<Bytes, ApiFailure> {
+    let status = response.status();
+    let body = response.bytes().await?;
+
+    if status.is_success() {
+        return match error_message(&body) {
+            Some(message) => Err(ApiFailure {
+                kind: FailureKind::Rejected,
+                status: Some(status.as_u16()),
+                message,
+            }),
+            None => Ok(body),
+        };
+    }
+
+    let kind = match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => FailureKind::AuthFailure,
+        s if s.is_server_error() => FailureKind::ServerError,
+        _ => FailureKind::Rejected,
+    };
+    let message = error_message(&body).unwrap_or_else(|| {
+        let body = String::from_utf8_lossy(&body);
+        let body = body.trim();
+        if body.is_empty() {
+            status.to_string()
+        } else {
+            format!("{status}: {body}")
+        }
+    });
+
+    Err(ApiFailure {
+        kind,
+        status: Some(status.as_u16()),
+        message,
+    })
+}
+
+/// Decode a successful response body, treating unexpected content as a server error.
+fn decode_json<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, ApiFailure> {
+    serde_json::from_slice(body).map_err(|e| ApiFailure {
+        kind: FailureKind::ServerError,
+        status: None,
+        message: format!("Unexpected response from Zoraxy: {e}"),
+    })
+}
+
+/// Extract the message from a Zoraxy `{"error": "..."}` body.
+fn error_message(body: &[u8]) -> Option<String> {
+    match serde_json::from_slice(body).ok()? {
+        serde_json::Value::Object(mut object) => match object.remove("error")? {
+            serde_json::Value::String(message) => Some(message),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn check(status: u16, body: &str) -> Result<Bytes, ApiFailure> {
+        let response = axum::http::Response::builder()
+            .status(status)
+            .body(body.to_string())
+            .unwrap();
+        check_response(Response::from(response)).await
+    }
+
+    #[tokio::test]
+    async fn success_with_an_error_body_is_rejected() {
+        let failure = check(200, r#"{"error": "invalid IP"}"#).await.unwrap_err();
+        assert_eq!(failure.kind, FailureKind::Rejected);
+        assert_eq!(failure.status, Some(200));
+        assert_eq!(failure.message, "invalid IP");
+        assert!(!failure.is_transient());
+
+        assert_eq!(check(200, r#""OK""#).await.unwrap(), r#""OK""#);
+        assert!(check(200, r#"{"error": 1}"#).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn unauthorized_and_forbidden_are_auth_failures() {
+        for status in [401, 403] {
+            let failure = check(status, "").await.unwrap_err();
+            assert_eq!(failure.kind, FailureKind::AuthFailure);
+            assert_eq!(failure.status, Some(status));
+            assert!(!failure.is_transient());
+        }
+    }
+
+    #[tokio::test]
+    async fn server_errors_timeouts_and_rate_limits_are_transient() {
+        let failure = check(502, "upstream down").await.unwrap_err();
+        assert_eq!(failure.kind, FailureKind::ServerError);
+        assert_eq!(failure.message, "502 Bad Gateway: upstream down");
+        assert!(failure.is_transient());
+
+        for status in [408, 429] {
+            let failure = check(status, "").await.unwrap_err();
+            assert_eq!(failure.kind, FailureKind::Rejected);
+            assert!(failure.is_transient(), "{status} should be retried");
+        }
+
+        let failure = check(400, r#"{"error": "bad request"}"#).await.unwrap_err();
+        assert_eq!(failure.kind, FailureKind::Rejected);
+        assert_eq!(failure.message, "bad request");
+        assert!(!failure.is_transient());
+    }
+}